@@ -1,9 +1,27 @@
 use serde_derive::{Deserialize, Serialize};
 use interfaces::Interface;
-use std::process;
 use sha256::digest;
-use github_device_flow::authorize;
+use github_device_flow::DeviceFlow;
 use std::fs;
+use std::fmt;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use std::time::Duration;
+use chrono::Utc;
+use reqwest::header::HeaderValue;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+use crate::error::{CopilotError, CopilotResult};
+
+const CLIENT_ID: &str = "Iv1.b507a08c87ecfe98";
+const GITHUB_HOST: &str = "github.com";
+const TOKEN_URL: &str = "https://api.github.com/copilot_internal/v2/token";
+// device codes expire after 15 minutes, polling happens every 5 seconds
+const POLL_ITERATIONS: u32 = 180;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CopilotTokenGrant {
@@ -19,76 +37,450 @@ pub struct CopilotTokenGrant {
   pub tracking_id: String
 }
 
+#[cfg(test)]
+impl CopilotTokenGrant {
+  pub(crate) fn expiring_in(refresh_in: i128, expires_in: i128) -> Self {
+    Self {
+      chat_enabled: false,
+      code_quote_enabled: false,
+      copilotignore_enabled: false,
+      expires_at: Utc::now().timestamp() as i128 + expires_in,
+      public_suggestions: String::new(),
+      refresh_in,
+      sku: String::new(),
+      telemetry: String::new(),
+      token: "tid=test".to_string(),
+      tracking_id: String::new(),
+    }
+  }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct HostsFile {
+  #[serde(rename = "github.com")]
   github_com: UserCredentials,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct UserCredentials {
   user: String,
   oauth_token: String,
 }
 
-pub fn create_hosts_file(path: &String) {
-  let auth_data = authorize("Iv1.b507a08c87ecfe98".to_string(), None);
-  println!("Creating hosts file at {}", path);
-  println!("Auth data: {:?}", auth_data);
+#[derive(Deserialize, Debug)]
+struct GithubUser {
+  login: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SignInStatus {
+  OK,
+  MaybeOk,
+  NotSignedIn,
+  NotAuthorized,
+  AlreadySignedIn,
+  PromptUserDeviceFlow,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusResponse {
+  pub status: SignInStatus,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SignInInitiateResponse {
+  pub status: SignInStatus,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub user: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub user_code: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub verification_uri: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub expires_in: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub interval: Option<u32>,
+}
+
+fn hosts_path() -> CopilotResult<String> {
+  let home = std::env::var("HOME").map_err(|_| CopilotError::Auth("HOME is not set".to_string()))?;
+  Ok(format!("{}/.config/github-copilot/hosts.json", home))
+}
+
+fn load_credentials(path: &str) -> Option<UserCredentials> {
+  let contents = fs::read_to_string(path).ok()?;
+  serde_json::from_str::<HostsFile>(&contents).ok().map(|hosts| hosts.github_com)
+}
+
+// hosts.json is shared with the other copilot plugins, entries for other hosts are kept as they are
+fn read_hosts(path: &str) -> CopilotResult<Map<String, Value>> {
+  if !Path::new(path).exists() {
+    return Ok(Map::new());
+  }
+  serde_json::from_str(&fs::read_to_string(path)?)
+    .map_err(|e| CopilotError::Auth(format!("{} is malformed: {}", path, e)))
+}
+
+// the file holds oauth tokens, so it is only readable by the user when we create it
+fn write_hosts(path: &str, hosts: &Map<String, Value>) -> CopilotResult<()> {
+  if let Some(parent) = Path::new(path).parent() {
+    fs::create_dir_all(parent)?;
+  }
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  options.mode(0o600);
+  options.open(path)?.write_all(serde_json::to_string(hosts)?.as_bytes())?;
+  Ok(())
+}
+
+fn save_credentials(path: &str, credentials: &UserCredentials) -> CopilotResult<()> {
+  let mut hosts = read_hosts(path)?;
+  hosts.insert(GITHUB_HOST.to_string(), serde_json::to_value(credentials)?);
+  write_hosts(path, &hosts)
 }
 
-pub fn read_config() -> String {
-  let fp = format!("{}/.config/github-copilot/hosts.json", std::env::var("HOME").unwrap());
-  let exists = std::path::Path::new(&fp).exists();
-  if !exists { create_hosts_file(&fp); }
-  println!("Exists: {}", exists);
+fn get_user_login(oauth_token: &str) -> CopilotResult<String> {
+  let user = reqwest::blocking::Client::new()
+    .get("https://api.github.com/user")
+    .header("Authorization", format!("token {}", oauth_token))
+    .header("Accept", "application/json")
+    .header("User-Agent", "Rust")
+    .send()?
+    .error_for_status()?
+    .json::<GithubUser>()?;
+  Ok(user.login)
+}
 
-  // if !exists { create_hosts_file(&fp); }
-  serde_json::from_str::<HostsFile>(
-    &fs::read_to_string(fp)
-    .unwrap()
-    .replace('.', "_")
-  ).unwrap().github_com.oauth_token
+// blocks until the user approves the device code, then persists the credentials
+fn complete_device_flow(mut flow: DeviceFlow, path: &str) -> CopilotResult<UserCredentials> {
+  let credential = flow.poll(POLL_ITERATIONS)?;
+  let credentials = UserCredentials {
+    user: get_user_login(&credential.token)?,
+    oauth_token: credential.token,
+  };
+  save_credentials(path, &credentials)?;
+  Ok(credentials)
 }
 
-pub async fn get_copilot_token() -> Result<CopilotTokenGrant, reqwest::Error> {
-  let user_token = read_config();
-  let url = "https://api.github.com/copilot_internal/v2/token".to_string();
+async fn fetch_copilot_token(url: &str, user_token: &str) -> CopilotResult<CopilotTokenGrant> {
   let client: reqwest::Client = reqwest::Client::new();
   let res = client.get(url)
     .bearer_auth(user_token)
     .header("editor-plugin-version", "copilot-intellij/1.2.8.2631")
     .header("editor-version", "JetBrains-IC/231.9011.34")
     .header("User-Agent", "Rust")
-    .send().await?;
-  let token_grant = res.json::<CopilotTokenGrant>().await.unwrap();
+    .send().await?
+    .error_for_status()?;
+  let token_grant = res.json::<CopilotTokenGrant>().await?;
   Ok(token_grant)
 }
 
-pub fn get_machine_id() -> String {
-  fn try_get_mac_addr() -> Result<String,()> {
-    fn validate_interface(itf: &Interface) -> bool {
-      let exclude = ["00:00:00:00:00:00", "ff:ff:ff:ff:ff:ff", "ac:de:48:00:11:22"];
-      return itf.is_running() &&
-        !exclude.iter().any(|&s| s == itf.hardware_addr()
-          .unwrap()
-          .as_string()
-        );
+fn bearer_header(token: &str) -> CopilotResult<HeaderValue> {
+  let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+    .map_err(|_| CopilotError::Protocol("copilot token is not a valid header value".to_string()))?;
+  value.set_sensitive(true);
+  Ok(value)
+}
+
+// refresh this long before the grant actually expires
+const EXPIRY_MARGIN_SECS: i128 = 60;
+const MIN_REFRESH_SECS: i128 = 10;
+const RETRY_SECS: u64 = 30;
+// github defaults for the device flow, the crate does not expose the real values
+const DEVICE_CODE_EXPIRES_IN: u32 = 900;
+const DEVICE_CODE_INTERVAL: u32 = 5;
+
+/// Holds the signed in user and the current copilot token, and keeps the token fresh.
+/// The authorization header is swapped in place so requests always pick up the latest grant.
+pub struct TokenManager {
+  token_url: String,
+  credentials: RwLock<Option<UserCredentials>>,
+  header: RwLock<Option<HeaderValue>>,
+  pending_flow: Mutex<Option<DeviceFlow>>,
+  refresh_task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Default for TokenManager {
+  fn default() -> Self {
+    Self {
+      token_url: TOKEN_URL.to_string(),
+      credentials: RwLock::new(None),
+      header: RwLock::new(None),
+      pending_flow: Mutex::new(None),
+      refresh_task: Mutex::new(None),
+    }
+  }
+}
+
+impl fmt::Debug for TokenManager {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("TokenManager")
+      .field("user", &self.user())
+      .field("signed_in", &self.header.read().unwrap().is_some())
+      .finish()
+  }
+}
+
+impl TokenManager {
+  /// Picks up an existing hosts.json if there is one, otherwise starts signed out.
+  pub async fn new() -> Arc<Self> {
+    let manager = Arc::new(Self::default());
+    if let Some(credentials) = hosts_path().ok().and_then(|path| load_credentials(&path)) {
+      if let Err(e) = manager.sign_in_with(credentials).await {
+        eprintln!("Failed to fetch copilot token: {}", e);
+      }
+    }
+    manager
+  }
+
+  /// Signed in as `user` without a token yet, grants are fetched from `token_url`.
+  #[cfg(test)]
+  pub(crate) fn for_tests(token_url: String, user: &str) -> Self {
+    let credentials = UserCredentials { user: user.to_string(), oauth_token: "gho_test".to_string() };
+    Self { token_url, credentials: RwLock::new(Some(credentials)), ..Self::default() }
+  }
+
+  pub fn auth_header(&self) -> Option<HeaderValue> {
+    self.header.read().unwrap().clone()
+  }
+
+  pub fn user(&self) -> Option<String> {
+    self.credentials.read().unwrap().as_ref().map(|c| c.user.to_string())
+  }
+
+  /// Fetch a new grant and swap the header, returning how long until the next refresh is due.
+  pub async fn refresh(&self) -> CopilotResult<Duration> {
+    let user_token = match self.credentials.read().unwrap().as_ref() {
+      Some(credentials) => credentials.oauth_token.to_string(),
+      None => return Ok(Duration::from_secs(RETRY_SECS))
+    };
+    let grant = fetch_copilot_token(&self.token_url, &user_token).await?;
+    *self.header.write().unwrap() = Some(bearer_header(&grant.token)?);
+    Ok(refresh_delay(&grant))
+  }
+
+  // the loop starts even when the first fetch fails, e.g. offline at startup, and retries it
+  async fn sign_in_with(self: &Arc<Self>, credentials: UserCredentials) -> CopilotResult<()> {
+    *self.credentials.write().unwrap() = Some(credentials);
+    let refreshed = self.refresh().await;
+    self.spawn_refresh_loop(*refreshed.as_ref().unwrap_or(&Duration::from_secs(RETRY_SECS)));
+    refreshed.map(|_| ())
+  }
+
+  fn spawn_refresh_loop(self: &Arc<Self>, first_delay: Duration) {
+    let manager = Arc::clone(self);
+    let task = tokio::spawn(async move {
+      let mut delay = first_delay;
+      loop {
+        tokio::time::sleep(delay).await;
+        delay = match manager.refresh().await {
+          Ok(next) => next,
+          Err(e) => {
+            eprintln!("Failed to refresh copilot token: {}", e);
+            Duration::from_secs(RETRY_SECS)
+          }
+        };
+      }
+    });
+    if let Some(previous) = self.refresh_task.lock().unwrap().replace(task) {
+      previous.abort();
     }
-    let ifaces = Interface::get_all().unwrap();
-    let itf = ifaces.iter().find(|&itf| validate_interface(itf));
-    match itf {
-      Some(x) => Ok(
-        x.hardware_addr()
-          .unwrap()
-          .to_string()),
-      None => Err(())
+  }
+
+  pub async fn sign_in_initiate(&self) -> CopilotResult<SignInInitiateResponse> {
+    if let Some(user) = self.user() {
+      return Ok(SignInInitiateResponse {
+        status: SignInStatus::AlreadySignedIn,
+        user: Some(user),
+        user_code: None,
+        verification_uri: None,
+        expires_in: None,
+        interval: None,
+      });
     }
+    let flow = tokio::task::spawn_blocking(|| DeviceFlow::start(CLIENT_ID, None)).await??;
+    let response = SignInInitiateResponse {
+      status: SignInStatus::PromptUserDeviceFlow,
+      user: None,
+      user_code: flow.user_code.clone(),
+      verification_uri: flow.verification_uri.clone(),
+      expires_in: Some(DEVICE_CODE_EXPIRES_IN),
+      interval: Some(DEVICE_CODE_INTERVAL),
+    };
+    *self.pending_flow.lock().unwrap() = Some(flow);
+    Ok(response)
+  }
+
+  pub async fn sign_in_confirm(self: &Arc<Self>, user_code: Option<String>) -> CopilotResult<StatusResponse> {
+    let flow = {
+      let mut pending = self.pending_flow.lock().unwrap();
+      match pending.as_ref() {
+        Some(flow) if user_code.is_none() || flow.user_code == user_code => pending.take(),
+        _ => None
+      }
+    };
+    let Some(flow) = flow else {
+      return Ok(StatusResponse { status: SignInStatus::NotSignedIn, user: self.user() });
+    };
+    let path = hosts_path()?;
+    let credentials = tokio::task::spawn_blocking(move || complete_device_flow(flow, &path)).await??;
+    let user = Some(credentials.user.to_string());
+    let status = match self.sign_in_with(credentials).await {
+      Ok(_) => SignInStatus::OK,
+      Err(CopilotError::Auth(_)) => SignInStatus::NotAuthorized,
+      Err(_) => SignInStatus::MaybeOk,
+    };
+    Ok(StatusResponse { status, user })
   }
-  let mac_addr = try_get_mac_addr();
-  if mac_addr.is_err() {
-    eprintln!("Problem finding hardware address");
-    process::exit(1);
+
+  pub fn sign_out(&self) -> CopilotResult<StatusResponse> {
+    self.sign_out_at(&hosts_path()?)
+  }
+
+  fn sign_out_at(&self, path: &str) -> CopilotResult<StatusResponse> {
+    if let Some(task) = self.refresh_task.lock().unwrap().take() {
+      task.abort();
+    }
+    *self.credentials.write().unwrap() = None;
+    *self.header.write().unwrap() = None;
+    // the other copilot plugins may still be signed in through the rest of the file
+    let mut hosts = read_hosts(path)?;
+    if hosts.remove(GITHUB_HOST).is_some() {
+      write_hosts(path, &hosts)?;
+    }
+    Ok(StatusResponse { status: SignInStatus::NotSignedIn, user: None })
+  }
+
+  pub async fn check_status(&self, local_checks_only: bool) -> StatusResponse {
+    let user = self.user();
+    if user.is_none() {
+      return StatusResponse { status: SignInStatus::NotSignedIn, user };
+    }
+    if local_checks_only {
+      return StatusResponse { status: SignInStatus::MaybeOk, user };
+    }
+    let status = match self.refresh().await {
+      Ok(_) => SignInStatus::OK,
+      // only a rejected token means the user lost access, server errors say nothing about it
+      Err(CopilotError::Auth(_)) => SignInStatus::NotAuthorized,
+      Err(_) => SignInStatus::MaybeOk,
+    };
+    StatusResponse { status, user }
+  }
+}
+
+fn refresh_delay(grant: &CopilotTokenGrant) -> Duration {
+  let until_expiry = grant.expires_at - Utc::now().timestamp() as i128 - EXPIRY_MARGIN_SECS;
+  let secs = grant.refresh_in.min(until_expiry).max(MIN_REFRESH_SECS);
+  Duration::from_secs(secs as u64)
+}
+
+/// A hash of the first running interface's hardware address, or of a random id when there is none.
+pub fn get_machine_id() -> String {
+  fn try_get_mac_addr() -> Option<String> {
+    let exclude = ["00:00:00:00:00:00", "ff:ff:ff:ff:ff:ff", "ac:de:48:00:11:22"];
+    Interface::get_all().ok()?
+      .iter()
+      .filter(|itf| itf.is_running())
+      .filter_map(|itf| itf.hardware_addr().ok())
+      .map(|addr| addr.as_string())
+      .find(|addr| !exclude.contains(&addr.as_str()))
   }
-  digest(mac_addr.unwrap())
+  let mac_addr = try_get_mac_addr().unwrap_or_else(|| {
+    eprintln!("Problem finding hardware address, using a random machine id");
+    Uuid::new_v4().to_string()
+  });
+  digest(mac_addr)
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(format!("copilot-rs-{}-{}", name, Uuid::new_v4())).to_string_lossy().to_string()
+  }
+
+  #[test]
+  fn refreshes_before_expiry() {
+    assert_eq!(refresh_delay(&CopilotTokenGrant::expiring_in(1500, 1800)), Duration::from_secs(1500));
+    // an early expiry wins over refresh_in, keeping the margin, but never below the minimum
+    let delay = refresh_delay(&CopilotTokenGrant::expiring_in(1500, 300)).as_secs() as i128;
+    assert!((300 - EXPIRY_MARGIN_SECS - 1..=300 - EXPIRY_MARGIN_SECS).contains(&delay));
+    assert_eq!(refresh_delay(&CopilotTokenGrant::expiring_in(1500, 0)), Duration::from_secs(MIN_REFRESH_SECS as u64));
+  }
+
+  #[test]
+  fn ignores_malformed_hosts_files() {
+    let path = temp_path("hosts");
+    assert!(load_credentials(&path).is_none());
+    fs::write(&path, "{\"github.com\": {\"user\": \"octocat\"}}").unwrap();
+    assert!(load_credentials(&path).is_none());
+    fs::write(&path, "{\"github.com\": {\"user\": \"octocat\", \"oauth_token\": \"gho_x\"}}").unwrap();
+    assert_eq!(load_credentials(&path).map(|c| c.user).as_deref(), Some("octocat"));
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn saves_only_the_github_entry() {
+    let path = temp_path("hosts");
+    fs::write(&path, "{\"ghe.example.com\": {\"user\": \"monalisa\", \"oauth_token\": \"ghu_y\"}}").unwrap();
+    let credentials = UserCredentials { user: "octocat".to_string(), oauth_token: "gho_x".to_string() };
+    save_credentials(&path, &credentials).unwrap();
+    let hosts = read_hosts(&path).unwrap();
+    assert_eq!(hosts["ghe.example.com"]["user"], "monalisa");
+    assert_eq!(load_credentials(&path).map(|c| c.user).as_deref(), Some("octocat"));
+    fs::remove_file(&path).unwrap();
+
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      save_credentials(&path, &credentials).unwrap();
+      assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+      fs::remove_file(&path).unwrap();
+    }
+  }
+
+  #[test]
+  fn sign_out_forgets_the_user() {
+    let path = temp_path("hosts");
+    fs::write(&path, "{\"ghe.example.com\": {\"user\": \"monalisa\", \"oauth_token\": \"ghu_y\"}}").unwrap();
+    let credentials = UserCredentials { user: "octocat".to_string(), oauth_token: "gho_x".to_string() };
+    save_credentials(&path, &credentials).unwrap();
+    let manager = TokenManager::default();
+    *manager.credentials.write().unwrap() = Some(credentials);
+    *manager.header.write().unwrap() = Some(bearer_header("tid=test").unwrap());
+
+    let status = manager.sign_out_at(&path).unwrap();
+    assert_eq!(status.status, SignInStatus::NotSignedIn);
+    assert!(manager.user().is_none() && manager.auth_header().is_none());
+    assert!(load_credentials(&path).is_none());
+    assert_eq!(read_hosts(&path).unwrap()["ghe.example.com"]["user"], "monalisa");
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn keeps_refreshing_after_a_failed_sign_in() {
+    let (url, _) = crate::retry::mock_server::serve(vec![("502 Bad Gateway", String::new())]).await;
+    let manager = Arc::new(TokenManager { token_url: format!("{}/token", url), ..TokenManager::default() });
+    let credentials = UserCredentials { user: "octocat".to_string(), oauth_token: "gho_x".to_string() };
+    assert!(manager.sign_in_with(credentials).await.is_err());
+    assert_eq!(manager.user().as_deref(), Some("octocat"));
+    assert!(manager.auth_header().is_none());
+    assert!(manager.refresh_task.lock().unwrap().is_some());
+  }
+
+  #[tokio::test]
+  async fn confirm_without_a_pending_flow() {
+    let manager = Arc::new(TokenManager::default());
+    let status = manager.sign_in_confirm(Some("ABCD-1234".to_string())).await.unwrap();
+    assert_eq!(status.status, SignInStatus::NotSignedIn);
+    assert!(status.user.is_none());
+  }
+}
@@ -1,5 +1,14 @@
-use crate::{parse, debounce, cache, request::build_request};
-use crate::copilot::{CopilotCompletionResponse, CopilotResponse, CopilotCyclingCompletion, CopilotEditorInfo, DocParams};
+use crate::parse::PositionEncoding;
+use crate::cache::{CacheLimits, CacheStats};
+use crate::{parse, debounce, cache, tokenizer, auth::{TokenManager, SignInInitiateResponse, StatusResponse}, };
+use crate::config::{self, CompletionSettings, Config};
+use crate::disk_cache::{DiskCache, DiskCacheOptions};
+use crate::inline_completion::{self, InlineCompletionParams, InlineCompletionList, InlineCompletionItem, InlineCompletionTriggerKind};
+use crate::provider::Providers;
+use crate::error::{CopilotError, CopilotResult};
+use crate::git::Repositories;
+use crate::{language::{self, Language}, snippets, postprocess};
+use crate::copilot::{balance_completion, collect_completions, Completion, on_cancel, CopilotCompletionResponse, CopilotResponse, CopilotCyclingCompletion, CopilotEditorInfo, DocParams};
 use futures_util::stream::PollNext;
 use ropey::Rope;
 use reqwest::RequestBuilder;
@@ -12,7 +21,8 @@ use std::{
   str::FromStr,
   fmt::Debug,
   collections::HashMap,
-  time::{Duration, Instant},
+  path::PathBuf,
+  time::Duration,
   sync::{
     mpsc::channel, RwLock, Arc, Mutex
   }
@@ -33,69 +43,49 @@ impl Success {
   pub fn new(success: bool) -> Self { Self { success } }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone)]
 pub struct TextDocumentItem {
   pub uri: String,
-  pub text: String,
+  pub rope: Rope,
   pub version: i32,
   pub language_id: String
 }
 
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct EmptyParams {}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SignInConfirmParams {
+  pub user_code: Option<String>
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckStatusParams {
+  #[serde(default)]
+  pub local_checks_only: bool
+}
+
 type SafeMap = Arc<RwLock<HashMap<String, Mutex<TextDocumentItem>>>>;
 
 #[derive(Debug)]
 pub struct Backend {
   pub client: Client,
   pub documents: SafeMap,
-  pub http_client: Arc<reqwest::Client>,
-  pub current_dispatch: Option<AbortHandle>,
+  pub providers: Providers,
+  pub token: Arc<TokenManager>,
+  /// The request in flight per method, a newer one for the same method aborts it.
+  pub current_dispatch: Mutex<HashMap<&'static str, AbortHandle>>,
   pub runner: debounce::Runner,
   pub editor_info: Arc<RwLock<CopilotEditorInfo>>,
+  pub position_encoding: Arc<RwLock<PositionEncoding>>,
+  pub config: Arc<RwLock<Config>>,
+  pub repositories: Repositories,
+  pub workspace_folders: Arc<RwLock<Vec<PathBuf>>>,
   pub cache: cache::CopilotCache
 }
 
-fn handle_event(
-  event: eventsource_stream::Event
-) -> CopilotResponse {
-  if event.data == "[DONE]" {
-    return CopilotResponse::Done;
-  }
-  match serde_json::from_str(&event.data) {
-    Ok(data) => { CopilotResponse::Answer(data) }
-    Err(e) => { CopilotResponse::Error(e.to_string()) }
-  }
-}
-
-pub async fn await_stream(req: RequestBuilder, line_before: String, pos: Position) -> Vec<String> {
-  let resp = req.send().await.unwrap();
-  let mut stream = resp.bytes_stream().eventsource();
-  let mut completion_list = Vec::<String>::with_capacity(4);
-  let mut s = "".to_string();
-  let mut cancellation_reason = None;
-
-  while let Some(event) = stream.next().await {
-    match handle_event(event.unwrap()) {
-      CopilotResponse::Answer(ans) => {
-        ans.choices.iter().for_each(|x| {
-          s.push_str(&x.text);
-          if x.finish_reason.is_some() {
-            completion_list.push(s.to_string());
-            s = "".to_string();
-          }
-        });
-      },
-      CopilotResponse::Done => {
-        return completion_list;
-      },
-      CopilotResponse::Error(e) => {
-        cancellation_reason = Some(e);
-      }
-    }
-  };
-  return completion_list;
-}
-
 struct CompletionStreamingParams {
   req: RequestBuilder,
   line_before: String,
@@ -103,7 +93,7 @@ struct CompletionStreamingParams {
 }
 
 impl Backend {
-  fn get_doc_info(&self, uri: &String) -> Result<Box<TextDocumentItem>> {
+  fn get_doc_info(&self, uri: &String) -> CopilotResult<Box<TextDocumentItem>> {
     let data = Arc::clone(&self.documents);
     let map = data.read().unwrap();
     match map.get(uri) {
@@ -111,13 +101,7 @@ impl Backend {
         let element = e.lock().expect("RwLock poisoned");
         Ok(Box::new(element.clone()))
       },
-      None => {
-        Err(Error {
-          code: tower_lsp::jsonrpc::ErrorCode::from(69),
-          data: None,
-          message: Cow::from(format!("Failed to get doc info"))
-        })
-      }
+      None => Err(CopilotError::UnknownDocument(uri.to_string()))
     }
   }
 
@@ -128,32 +112,143 @@ impl Backend {
     *lock = params;
     return Ok(Success::new(true));
   }
-  pub fn get_doc_params(&self, params: &CompletionParams) -> DocParams {
+  pub async fn sign_in_initiate(&self, _: EmptyParams) -> Result<SignInInitiateResponse> {
+    Ok(self.token.sign_in_initiate().await?)
+  }
+
+  pub async fn sign_in_confirm(&self, params: SignInConfirmParams) -> Result<StatusResponse> {
+    Ok(self.token.sign_in_confirm(params.user_code).await?)
+  }
+
+  pub async fn sign_out(&self, _: EmptyParams) -> Result<StatusResponse> {
+    Ok(self.token.sign_out()?)
+  }
+
+  pub async fn check_status(&self, params: CheckStatusParams) -> Result<StatusResponse> {
+    Ok(self.token.check_status(params.local_checks_only).await)
+  }
+
+  pub async fn get_cache_stats(&self, _: EmptyParams) -> Result<CacheStats> {
+    Ok(self.cache.stats())
+  }
+
+  async fn update_config(&self, settings: &Value) {
+    if settings.is_null() {
+      return;
+    }
+    let merged = self.config.read().unwrap().merged(settings);
+    match merged {
+      Ok(config) => {
+        self.repositories.set_enterprise_hosts(config.ghe_hosts.clone());
+        *self.config.write().unwrap() = config;
+      },
+      Err(e) => self.client.log_message(MessageType::ERROR, e.to_string()).await,
+    }
+  }
+
+  pub fn settings_for(&self, language: &str) -> CompletionSettings {
+    self.config.read().unwrap().for_language(language)
+  }
+
+  fn relative_path(&self, uri: &str) -> Option<String> {
+    let path = Url::parse(uri).ok()?.to_file_path().ok()?;
+    Some(language::relative_path(&path, &self.workspace_folders.read().unwrap()))
+  }
+
+  // the file path relative to its workspace folder, commented out in the document's language
+  fn path_header(&self, doc_params: &DocParams) -> Option<String> {
+    language::path_header(&doc_params.language, &self.relative_path(&doc_params.uri)?)
+  }
+
+  // similar code from the other open documents of the same language
+  fn neighbor_snippets(&self, doc_params: &DocParams, budget: usize) -> String {
+    let Some(language) = Language::find(&doc_params.language) else {
+      return String::new();
+    };
+    // ropes clone in constant time, the locks are released before any text is looked at
+    let ropes: Vec<(String, Rope)> = self.documents.read().unwrap().values()
+      .filter_map(|doc| {
+        let doc = doc.lock().unwrap();
+        if doc.uri == doc_params.uri || doc.language_id != doc_params.language {
+          return None;
+        }
+        Some((doc.uri.to_string(), doc.rope.clone()))
+      })
+      .collect();
+    let documents: Vec<(String, Rope)> = ropes.into_iter()
+      .map(|(uri, rope)| (self.relative_path(&uri).unwrap_or(uri), rope))
+      .collect();
+    snippets::neighbor_snippets(language, &doc_params.prefix, &documents, budget)
+  }
+
+  pub fn encoding(&self) -> PositionEncoding {
+    *self.position_encoding.read().unwrap()
+  }
+
+  pub fn get_doc_params(&self, params: &CompletionParams) -> CopilotResult<DocParams> {
     let pos = params.text_document_position.position.clone();
     let uri = params.text_document_position.text_document.uri.to_string();
-    let doc = self.get_doc_info(&uri).unwrap();
-    let rope = ropey::Rope::from_str(&doc.text);
-    let offset = parse::position_to_offset(pos, &rope).unwrap();
+    let doc = self.get_doc_info(&uri)?;
+    let rope = doc.rope;
+    let encoding = self.encoding();
+    let offset = parse::position_to_offset(pos, &rope, encoding)?;
 
-    DocParams {
+    Ok(DocParams {
       uri: uri.to_string(),
       pos: pos.clone(),
       language: doc.language_id.to_string(),
-      prefix: parse::get_text_before(offset, &rope).unwrap(),
-      suffix: parse::get_text_after(offset, &rope).unwrap(),
-      line_before: parse::get_line_before(pos, &rope).unwrap().to_string(),
+      prefix: parse::get_text_before(offset, &rope).ok_or(CopilotError::InvalidPosition(pos))?,
+      suffix: parse::get_text_after(offset, &rope).ok_or(CopilotError::InvalidPosition(pos))?,
+      line_before: parse::get_line_before(pos, &rope, encoding)?,
+      nwo: self.repositories.nwo_for(&uri),
+      next_indent: parse::next_indent(&rope, pos.line as usize),
       rope,
-    }
+    })
   }
 
   pub async fn get_completions_cycling(&self, params: CompletionParams) -> Result<CopilotCompletionResponse> {
-    let doc_params = self.get_doc_params(&params);
-    let cached_result = self.cache.get_cached_result(&doc_params.uri, doc_params.pos.line);
+    let doc_params = self.get_doc_params(&params)?;
+    self.complete("getCompletionsCycling", params, doc_params, false).await
+  }
+
+  pub async fn inline_completion(&self, params: InlineCompletionParams) -> Result<Option<InlineCompletionList>> {
+    let invoked = params.context.trigger_kind == InlineCompletionTriggerKind::INVOKED;
+    let completion_params = params.to_completion_params();
+    let doc_params = self.get_doc_params(&completion_params)?;
+    let line_before = doc_params.line_before.to_string();
+    let response = self.complete("textDocument/inlineCompletion", completion_params, doc_params, invoked).await?;
+    let items = response.completions.iter()
+      .filter(|completion| match &params.context.selected_completion_info {
+        Some(selected) => inline_completion::matches_selected(completion, &line_before, selected, self.encoding()),
+        None => true
+      })
+      .map(InlineCompletionItem::from_cycling)
+      .collect();
+    Ok(Some(InlineCompletionList { items }))
+  }
+
+  // explicitly invoked requests skip the debounce but still supersede pending ones of the same `method`,
+  // clients asking for completions and inline completions on every keystroke keep getting both.
+  // `doc_params` is the document as it was when the request came in
+  async fn complete(&self, method: &'static str, params: CompletionParams, doc_params: DocParams, invoked: bool) -> Result<CopilotCompletionResponse> {
+    let settings = self.settings_for(&doc_params.language);
+    let cached_result = self.cache.get_cached_result(&doc_params, &settings).await;
     if cached_result.is_some() {
       return Ok(cached_result.unwrap());
     }
 
-    let valid = self.runner.increment_and_do_stuff().await;
+    // whatever is still streaming belongs to an older cursor position
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    if let Some(previous) = self.current_dispatch.lock().unwrap().insert(method, abort_handle) {
+      previous.abort();
+    }
+
+    let valid = if invoked {
+      self.runner.increment(method);
+      true
+    } else {
+      self.runner.increment_and_do_stuff(method).await
+    };
     if !valid {
       return Ok(CopilotCompletionResponse {
         cancellation_reason: Some("More Recent".to_string()),
@@ -161,41 +256,125 @@ impl Backend {
       });
     }
 
-    let doc_params = self.get_doc_params(&params);
+    let doc_params = self.get_doc_params(&params)?;
+    match Abortable::new(self.fetch_completions(&settings, doc_params.clone()), abort_registration).await {
+      // rate limits and server errors are reported to the editor as a cancelled completion
+      Ok(Err(e)) => match e.cancellation_reason() {
+        Some(reason) => Ok(CopilotCompletionResponse {
+          cancellation_reason: Some(reason.to_string()),
+          completions: vec![]
+        }),
+        None => Err(e.into()),
+      },
+      Ok(Ok(response)) => {
+        if response.cancellation_reason.is_none() {
+          self.cache.set_cached_result(&doc_params, &settings, &response);
+        }
+        Ok(response)
+      },
+      Err(Aborted) => Ok(on_cancel().await)
+    }
+  }
+
+  // dropping this future drops the response body, which closes the connection.
+  // the cache stays keyed on the whole document, only the outgoing prompt is trimmed
+  async fn fetch_completions(&self, settings: &CompletionSettings, doc_params: DocParams) -> CopilotResult<CopilotCompletionResponse> {
+    let mode = postprocess::CompletionMode::at_cursor(&doc_params.prefix, &doc_params.suffix);
+    let mut settings = settings.clone();
+    settings.stop = mode.stop_sequences(&doc_params.language, &settings.stop);
+    let header = self.path_header(&doc_params).unwrap_or_default();
+    let snippets = self.neighbor_snippets(&doc_params, settings.max_snippet_tokens.min(settings.max_prompt_tokens / 2));
+    let prompt = prompt_params(&doc_params, &settings, &header, &snippets);
     let line_before = doc_params.line_before.to_string();
-    let http_client = Arc::clone(&self.http_client);
-    let _prompt = format!(
-      "// Path: {}\n{}",
-      doc_params.uri,
-      doc_params.prefix.to_string()
-    );
 
-    let req = build_request(http_client, doc_params.language, doc_params.prefix, doc_params.suffix);
+    // retries stay within one debounce delay, by then a newer request may be waiting
+    let provider = self.providers.get(settings.provider);
+    let completions = provider.complete(&settings, &prompt, self.runner.delay()).await?;
+    // a stream that broke off leaves a partial result, the reason keeps it out of the cache
+    let (completion_list, cancellation_reason) = collect_completions(completions).await;
+    let completion_list = completion_list.into_iter()
+      .filter_map(|completion| Some(Completion {
+        text: clean_completion(&completion.text, &settings, &doc_params)?,
+        ..completion
+      }))
+      .collect();
+    Ok(CopilotCompletionResponse {
+      cancellation_reason,
+      ..CopilotCompletionResponse::from_completions(completion_list, line_before, doc_params.pos, self.encoding())
+    })
+  }
+}
 
-    let completion_list = await_stream(
-      req,
-      line_before.to_string(),
-      params.text_document_position.position.clone()
-    );
-    let response = CopilotCompletionResponse::from_str_vec(
-      completion_list.await,
-      line_before,
-      doc_params.pos
-    );
-    self.cache.set_cached_result(&doc_params.uri, &doc_params.pos.line, &response);
-    Ok(response)
+// what is sent for the document: the path header and snippets ahead of the prefix, with both sides
+// trimmed to their token budgets. completions are still checked against the whole document
+fn prompt_params(doc_params: &DocParams, settings: &CompletionSettings, header: &str, snippets: &str) -> DocParams {
+  let prompt_budget = settings.max_prompt_tokens
+    .saturating_sub(tokenizer::count_tokens(header) + tokenizer::count_tokens(snippets));
+  DocParams {
+    prefix: format!("{}{}{}", header, snippets, tokenizer::trim_prefix(&doc_params.prefix, prompt_budget)),
+    suffix: tokenizer::trim_suffix(&doc_params.suffix, settings.max_suffix_tokens),
+    ..doc_params.clone()
   }
 }
 
+// the post-processing every completion goes through against the untrimmed document, `None` drops it
+fn clean_completion(text: &str, settings: &CompletionSettings, doc_params: &DocParams) -> Option<String> {
+  let mut text = postprocess::truncate_at_stop(text, &settings.stop);
+  if settings.trim_by_indentation {
+    text = postprocess::trim_by_indentation(&text, &doc_params.line_before);
+  }
+  text = postprocess::trim_suffix_overlap(&text, &doc_params.suffix);
+  if let Some(language) = Language::find(&doc_params.language) {
+    text = balance_completion(&text, &doc_params.prefix, &doc_params.suffix, language)?;
+  }
+  (!postprocess::repeats_suffix(&text, &doc_params.suffix)).then_some(text)
+}
+
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
-  async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+  async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+    let encoding = PositionEncoding::negotiate(
+      params.capabilities.general.as_ref().and_then(|general| general.position_encodings.as_ref())
+    );
+    *self.position_encoding.write().unwrap() = encoding;
+    let folders = match params.workspace_folders {
+      Some(folders) => folders.into_iter().map(|folder| folder.uri).collect(),
+      None => params.root_uri.into_iter().collect::<Vec<_>>(),
+    };
+    *self.workspace_folders.write().unwrap() = folders.iter()
+      .filter_map(|uri| uri.to_file_path().ok())
+      .collect();
+    if let Some(options) = params.initialization_options.as_ref() {
+      self.update_config(options).await;
+    }
+    // cache options live next to the completion settings, nested or not
+    let options = params.initialization_options.as_ref().map(config::section);
+    let cache_limits = options
+      .and_then(|options| options.get("cache"))
+      .and_then(|cache| serde_json::from_value::<CacheLimits>(cache.clone()).ok());
+    if let Some(limits) = cache_limits {
+      self.cache.set_limits(limits);
+    }
+    let disk_options = options
+      .and_then(|options| options.pointer("/cache/disk"))
+      .and_then(|disk| serde_json::from_value::<DiskCacheOptions>(disk.clone()).ok())
+      .filter(|disk| disk.enabled);
+    if let Some(disk_options) = disk_options {
+      // opening prunes the directory, which is blocking io
+      let opened = tokio::task::spawn_blocking(move || DiskCache::open(&disk_options)).await
+        .map_err(CopilotError::from)
+        .and_then(|opened| opened.map_err(CopilotError::from));
+      match opened {
+        Ok(disk) => self.cache.set_disk(Some(disk)),
+        Err(e) => self.client.log_message(MessageType::ERROR, format!("disk cache disabled: {}", e)).await,
+      }
+    }
     Ok(InitializeResult {
       server_info: None,
-      offset_encoding: None,
       capabilities: ServerCapabilities {
+        position_encoding: Some(encoding.kind()),
         inlay_hint_provider: None,
-        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::INCREMENTAL)),
         completion_provider: Some(CompletionOptions {
           resolve_provider: Some(false),
           trigger_characters: Some(vec![".".to_string()]),
@@ -204,7 +383,7 @@ impl LanguageServer for Backend {
           completion_item: None,
         }),
         execute_command_provider: Some(ExecuteCommandOptions {
-          commands: vec!["dummy.do_something".to_string()],
+          commands: vec![inline_completion::ACCEPT_COMMAND.to_string()],
           work_done_progress_options: Default::default(),
         }),
         workspace: Some(WorkspaceServerCapabilities {
@@ -229,12 +408,18 @@ impl LanguageServer for Backend {
     Ok(())
   }
 
+  async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+    let doc_params = self.get_doc_params(&params)?;
+    let response = self.complete("textDocument/completion", params, doc_params, false).await?;
+    Ok(Some(CompletionResponse::List(response.to_completion_list())))
+  }
+
   async fn did_open(&self, params: DidOpenTextDocumentParams) {
     self.client.log_message(MessageType::INFO, "file opened!").await;
     let id = params.text_document.uri.to_string();
     let doc = Mutex::new(TextDocumentItem {
       uri: params.text_document.uri.to_string(),
-      text: params.text_document.text,
+      rope: Rope::from_str(&params.text_document.text),
       version: params.text_document.version,
       language_id: params.text_document.language_id
     });
@@ -242,18 +427,27 @@ impl LanguageServer for Backend {
     map.entry(id).or_insert_with(|| doc);
   }
 
-  async fn did_change(&self, mut params: DidChangeTextDocumentParams) {
-    let data = Arc::clone(&self.documents);
-    let mut map = data.write().expect("RwLock poisoned");
-    if let Some(element) = map.get(&params.text_document.uri.to_string()) {
-      let mut element = element.lock().expect("Mutex poisoned");
-      let doc = TextDocumentItem {
-        uri: element.uri.to_string(),
-        text: std::mem::take(&mut params.content_changes[0].text),
-        version: params.text_document.version,
-        language_id: element.language_id.to_string()
-      };
-      *element = doc
+  async fn did_change(&self, params: DidChangeTextDocumentParams) {
+    let uri = params.text_document.uri.to_string();
+    let applied = {
+      let map = self.documents.read().expect("RwLock poisoned");
+      match map.get(&uri) {
+        Some(element) => {
+          let mut element = element.lock().expect("Mutex poisoned");
+          element.version = params.text_document.version;
+          params.content_changes.into_iter()
+            .try_for_each(|change| parse::apply_change(&mut element.rope, change, self.encoding()))
+        },
+        None => Ok(())
+      }
+    };
+    // a change that doesn't fit means our copy is out of sync, completing on it would use corrupt text
+    if let Err(e) = applied {
+      self.documents.write().expect("RwLock poisoned").remove(&uri);
+      self.cache.evict_document(&uri);
+      self.client
+        .log_message(MessageType::ERROR, format!("{} is out of sync ({}), reopen it to resume completions", uri, e))
+        .await;
     }
   }
 
@@ -262,22 +456,24 @@ impl LanguageServer for Backend {
       .log_message(MessageType::ERROR, "file saved!")
       .await;
   }
-  async fn did_close(&self, _: DidCloseTextDocumentParams) {
-    self.client
-      .log_message(MessageType::ERROR, "file closed!")
-      .await;
+  async fn did_close(&self, params: DidCloseTextDocumentParams) {
+    let uri = params.text_document.uri.to_string();
+    self.documents.write().expect("RwLock poisoned").remove(&uri);
+    self.cache.evict_document(&uri);
   }
 
-  async fn did_change_configuration(&self, _: DidChangeConfigurationParams) {
-    self.client
-      .log_message(MessageType::ERROR, "configuration changed!")
-      .await;
+  async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+    self.update_config(&params.settings).await;
   }
 
-  async fn did_change_workspace_folders(&self, _: DidChangeWorkspaceFoldersParams) {
-    self.client
-      .log_message(MessageType::ERROR, "workspace folders changed!")
-      .await;
+  async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+    self.repositories.clear();
+    let mut folders = self.workspace_folders.write().unwrap();
+    let removed: Vec<PathBuf> = params.event.removed.iter()
+      .filter_map(|folder| folder.uri.to_file_path().ok())
+      .collect();
+    folders.retain(|folder| !removed.contains(folder));
+    folders.extend(params.event.added.iter().filter_map(|folder| folder.uri.to_file_path().ok()));
   }
 
   async fn did_change_watched_files(&self, _: DidChangeWatchedFilesParams) {
@@ -286,19 +482,157 @@ impl LanguageServer for Backend {
       .await;
   }
 
-  async fn execute_command(&self, _: ExecuteCommandParams) -> Result<Option<Value>> {
-    self.client
-      .log_message(MessageType::ERROR, "command executed!")
-      .await;
-
-    match self.client.apply_edit(WorkspaceEdit::default()).await {
-      Ok(res) if res.applied => self.client.log_message(MessageType::INFO, "applied").await,
-      Ok(_) => self.client.log_message(MessageType::INFO, "rejected").await,
-      Err(err) => self.client.log_message(MessageType::ERROR, err).await,
+  async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<Value>> {
+    if params.command == inline_completion::ACCEPT_COMMAND {
+      self.client.log_message(MessageType::INFO, "completion accepted").await;
+    } else {
+      self.client
+        .log_message(MessageType::ERROR, format!("unknown command {}", params.command))
+        .await;
     }
 
     Ok(None)
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn backend(client: Client) -> Backend {
+    let token = Arc::new(TokenManager::default());
+    Backend {
+      client,
+      documents: Arc::new(RwLock::new(HashMap::new())),
+      providers: Providers::new(Arc::new(reqwest::Client::new()), Arc::clone(&token)),
+      token,
+      current_dispatch: Mutex::new(HashMap::new()),
+      runner: debounce::Runner::new(Duration::from_millis(100)),
+      editor_info: Arc::new(RwLock::new(CopilotEditorInfo::default())),
+      position_encoding: Arc::new(RwLock::new(PositionEncoding::default())),
+      config: Arc::new(RwLock::new(Config::default())),
+      repositories: Repositories::default(),
+      workspace_folders: Arc::new(RwLock::new(vec![])),
+      cache: cache::CopilotCache::new(),
+    }
+  }
+
+  fn open(backend: &Backend, uri: &str, text: &str) -> CompletionParams {
+    let document = TextDocumentItem { uri: uri.to_string(), rope: Rope::from_str(text), version: 0, language_id: "rust".to_string() };
+    backend.documents.write().unwrap().insert(uri.to_string(), Mutex::new(document));
+    let rope = Rope::from_str(text);
+    let position = parse::offset_to_position(rope.len_chars(), &rope, PositionEncoding::Utf16).unwrap();
+    CompletionParams {
+      text_document_position: TextDocumentPositionParams::new(TextDocumentIdentifier::new(Url::parse(uri).unwrap()), position),
+      work_done_progress_params: WorkDoneProgressParams::default(),
+      partial_result_params: PartialResultParams::default(),
+      context: None,
+    }
+  }
 
+  // a generate endpoint whose first answer never ends, `dropped` fires once the client hangs up on it
+  async fn serve_endless_then_done() -> (String, tokio::sync::oneshot::Receiver<()>, tokio::sync::oneshot::Receiver<()>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (started_tx, started) = tokio::sync::oneshot::channel();
+    let (dropped_tx, dropped) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+      let (mut endless, _) = listener.accept().await.unwrap();
+      crate::retry::mock_server::read_request(&mut endless).await;
+      let chunk = "{\"response\":\"fn \",\"done\":false}\n";
+      let head = format!("HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n{:x}\r\n{}\r\n", chunk.len(), chunk);
+      endless.write_all(head.as_bytes()).await.unwrap();
+      started_tx.send(()).unwrap();
+      tokio::spawn(async move {
+        let mut buf = [0; 64];
+        while matches!(endless.read(&mut buf).await, Ok(1..)) {}
+        dropped_tx.send(()).unwrap();
+      });
+
+      let (mut done, _) = listener.accept().await.unwrap();
+      crate::retry::mock_server::read_request(&mut done).await;
+      let body = "{\"response\":\"main() {}\",\"done\":true}\n";
+      let response = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}", body.len(), body);
+      done.write_all(response.as_bytes()).await.unwrap();
+    });
+    (url, started, dropped)
+  }
+
+  #[tokio::test]
+  async fn newer_requests_abort_the_stream_in_flight() {
+    let (url, started, dropped) = serve_endless_then_done().await;
+    let (service, _) = LspService::new(backend);
+    let backend = service.inner();
+    backend.update_config(&serde_json::json!({ "provider": "local", "endpoint": url })).await;
+    let params = open(backend, "file:///main.rs", "fn ");
+
+    let first = backend.complete("getCompletionsCycling", params.clone(), backend.get_doc_params(&params).unwrap(), true);
+    let second = async {
+      started.await.unwrap();
+      backend.complete("getCompletionsCycling", params.clone(), backend.get_doc_params(&params).unwrap(), true).await
+    };
+    let (first, second) = tokio::join!(first, second);
+    assert_eq!(first.unwrap().cancellation_reason.as_deref(), Some("RequestCancelled"));
+    assert_eq!(second.unwrap().completions[0].display_text, "main() {}");
+    tokio::time::timeout(Duration::from_secs(5), dropped).await.unwrap().unwrap();
+  }
+
+  #[tokio::test]
+  async fn retries_slow_server_errors_within_the_debounce_delay() {
+    let done = "{\"response\":\"main() {}\",\"done\":true}\n".to_string();
+    // each answer takes longer than the whole debounce delay
+    let (url, served) = crate::retry::mock_server::serve_after(
+      Duration::from_millis(150),
+      vec![("502 Bad Gateway", String::new()), ("200 OK", done)],
+    ).await;
+    let (service, _) = LspService::new(backend);
+    let backend = service.inner();
+    backend.update_config(&serde_json::json!({ "provider": "local", "endpoint": url })).await;
+    let params = open(backend, "file:///main.rs", "fn ");
+
+    let response = backend.complete("getCompletionsCycling", params.clone(), backend.get_doc_params(&params).unwrap(), true).await.unwrap();
+    assert_eq!(response.completions[0].display_text, "main() {}");
+    assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 2);
+  }
+
+  #[tokio::test]
+  async fn holds_off_until_retry_after_has_passed() {
+    let (url, served) = crate::retry::mock_server::serve(vec![("429 Too Many Requests\r\nretry-after: 30", String::new())]).await;
+    let (service, _) = LspService::new(backend);
+    let backend = service.inner();
+    backend.update_config(&serde_json::json!({ "provider": "local", "endpoint": url })).await;
+    let params = open(backend, "file:///main.rs", "fn ");
+
+    for _ in 0..2 {
+      let response = backend.complete("getCompletionsCycling", params.clone(), backend.get_doc_params(&params).unwrap(), true).await.unwrap();
+      assert_eq!(response.cancellation_reason.as_deref(), Some("RateLimited"));
+    }
+    assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn keeps_initialization_options_over_empty_configuration_changes() {
+    let (service, _) = LspService::new(backend);
+    let backend = service.inner();
+    backend.update_config(&serde_json::json!({ "copilot-rs": { "model": "gpt-3.5-turbo-instruct" } })).await;
+    backend.did_change_configuration(DidChangeConfigurationParams { settings: serde_json::json!({}) }).await;
+    assert_eq!(backend.settings_for("rust").model, "gpt-3.5-turbo-instruct");
+  }
+
+  #[test]
+  fn balances_against_the_untrimmed_document() {
+    let body = "    let x = 1;\n".repeat(200);
+    let doc_params = DocParams::from_text(&format!("fn main() {{\n{}    if x {{\n        ", body), "");
+    let settings = CompletionSettings { max_prompt_tokens: 64, trim_by_indentation: false, ..CompletionSettings::default() };
+
+    let prompt = prompt_params(&doc_params, &settings, "// Path: main.rs\n", "// Compare this snippet from lib.rs:\n// fn lib() {\n");
+    assert!(prompt.prefix.starts_with("// Path: main.rs\n// Compare"));
+    assert!(!prompt.prefix.contains("fn main()"));
+
+    // closing main is only right knowing it was opened above the trimmed prompt
+    let completion = "y();\n    }\n}";
+    assert_eq!(clean_completion(completion, &settings, &doc_params).as_deref(), Some(completion));
+    assert_eq!(clean_completion(completion, &settings, &prompt).as_deref(), Some("y();\n    }"));
+  }
+}
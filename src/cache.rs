@@ -2,87 +2,376 @@ use std::{
   borrow::Cow,
   str::FromStr,
   fmt::Debug,
-  collections::HashMap,
+  collections::{BTreeMap, HashMap},
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
   time::{Duration, Instant},
   sync::{
     mpsc::channel, RwLock, Arc, Mutex
   }
 };
+use serde_derive::{Deserialize, Serialize};
+use crate::config::CompletionSettings;
 use crate::copilot::{DocParams, CopilotCompletionResponse};
+use crate::disk_cache::{self, DiskCache};
 use tower_lsp::lsp_types::TextDocumentItem;
 
-// if file changes, keep the cache
-// if line number is different for an existing file, clean
+// rough per entry bookkeeping cost on top of the stored text
+const ENTRY_OVERHEAD: usize = 128;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CacheLimits {
+  pub max_entries: usize,
+  pub max_bytes: usize,
+}
+
+impl Default for CacheLimits {
+  fn default() -> Self {
+    Self { max_entries: 512, max_bytes: 16 * 1024 * 1024 }
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+  pub hits: u64,
+  pub disk_hits: u64,
+  pub misses: u64,
+  pub evictions: u64,
+  pub entries: usize,
+  pub bytes: usize,
+}
+
+// entries are keyed by everything except the text typed on the cursor line,
+// so a later request on the same line can find completions made for an earlier part of it
+#[derive(Debug)]
+struct CacheEntry {
+  key: u64,
+  uri: String,
+  line_before: String,
+  response: CopilotCompletionResponse,
+  size: usize,
+  last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+  entries: HashMap<u64, Vec<CacheEntry>>,
+  // every entry by `last_used`, least recently used first, pointing back at its key and line
+  recency: BTreeMap<u64, (u64, String)>,
+  limits: CacheLimits,
+  stats: CacheStats,
+  tick: u64,
+}
+
+/// Least recently used completion cache, bounded by entry count and approximate size in bytes.
+/// Misses fall through to the optional on-disk layer.
 #[derive(Debug)]
 pub struct CopilotCache {
-  inner: RwLock<HashMap<String, Mutex<CopilotCompletionResponse>>>,
-  last_line: RwLock<HashMap<String, Mutex<u32>>>,
-  pending: RwLock<Mutex<bool>>
+  inner: Mutex<CacheState>,
+  disk: RwLock<Option<Arc<DiskCache>>>,
+}
+
+fn prompt_key(doc_params: &DocParams, settings: &CompletionSettings) -> u64 {
+  let before_line = doc_params.prefix
+    .strip_suffix(&doc_params.line_before)
+    .unwrap_or(&doc_params.prefix);
+  let mut hasher = DefaultHasher::new();
+  settings.fingerprint().hash(&mut hasher);
+  before_line.hash(&mut hasher);
+  doc_params.suffix.hash(&mut hasher);
+  hasher.finish()
+}
+
+fn entry_size(line_before: &str, response: &CopilotCompletionResponse) -> usize {
+  let text: usize = response.completions.iter()
+    .map(|c| c.display_text.len() + c.text.len())
+    .sum();
+  ENTRY_OVERHEAD + line_before.len() + text
+}
+
+// keeps the completions the user has been typing along with, minus what was already typed
+fn type_through(response: &CopilotCompletionResponse, typed: &str, doc_params: &DocParams) -> Option<CopilotCompletionResponse> {
+  let completions: Vec<_> = response.completions.iter()
+    .filter_map(|completion| {
+      let rest = completion.display_text.strip_prefix(typed)?;
+      if rest.is_empty() {
+        return None;
+      }
+      let mut completion = completion.clone();
+      completion.display_text = rest.to_string();
+      completion.position = doc_params.pos;
+      Some(completion)
+    })
+    .collect();
+  if completions.is_empty() {
+    return None;
+  }
+  Some(CopilotCompletionResponse { completions, cancellation_reason: None })
+}
+
+impl CacheState {
+  fn next_tick(&mut self) -> u64 {
+    self.tick += 1;
+    self.tick
+  }
+
+  fn insert(&mut self, entry: CacheEntry) {
+    self.stats.entries += 1;
+    self.stats.bytes += entry.size;
+    self.recency.insert(entry.last_used, (entry.key, entry.line_before.to_string()));
+    self.entries.entry(entry.key).or_default().push(entry);
+  }
+
+  fn remove_where(&mut self, mut pred: impl FnMut(&CacheEntry) -> bool) -> usize {
+    let mut removed = 0;
+    let (stats, recency) = (&mut self.stats, &mut self.recency);
+    self.entries.retain(|_, entries| {
+      entries.retain(|entry| {
+        if !pred(entry) {
+          return true;
+        }
+        stats.entries -= 1;
+        stats.bytes -= entry.size;
+        recency.remove(&entry.last_used);
+        removed += 1;
+        false
+      });
+      !entries.is_empty()
+    });
+    removed
+  }
+
+  fn touch(&mut self, key: u64, line_before: &str) {
+    let tick = self.next_tick();
+    let Some(entry) = self.entries.get_mut(&key)
+      .and_then(|entries| entries.iter_mut().find(|entry| entry.line_before == line_before)) else {
+      return;
+    };
+    self.recency.remove(&entry.last_used);
+    entry.last_used = tick;
+    self.recency.insert(tick, (key, line_before.to_string()));
+  }
+
+  fn evict_to_limits(&mut self) {
+    while self.stats.entries > self.limits.max_entries || self.stats.bytes > self.limits.max_bytes {
+      let Some((_, (key, line_before))) = self.recency.pop_first() else { break };
+      let Some(entries) = self.entries.get_mut(&key) else { continue };
+      if let Some(idx) = entries.iter().position(|entry| entry.line_before == line_before) {
+        let entry = entries.swap_remove(idx);
+        self.stats.entries -= 1;
+        self.stats.bytes -= entry.size;
+        self.stats.evictions += 1;
+      }
+      if entries.is_empty() {
+        self.entries.remove(&key);
+      }
+    }
+  }
 }
 
 impl CopilotCache {
   pub fn new() -> Self {
+    Self::with_limits(CacheLimits::default())
+  }
+
+  pub fn with_limits(limits: CacheLimits) -> Self {
     Self {
-      inner: RwLock::new(HashMap::new()),
-      last_line: RwLock::new(HashMap::new()),
-      pending: RwLock::new(Mutex::new(false)),
+      inner: Mutex::new(CacheState { limits, ..CacheState::default() }),
+      disk: RwLock::new(None),
     }
   }
 
+  pub fn set_limits(&self, limits: CacheLimits) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.limits = limits;
+    inner.evict_to_limits();
+  }
 
-  fn get_last_line(&self, uri: &String) -> Option<u32> {
-    let inner = self.last_line.read().unwrap();
-    let last_line = inner.get(uri);
-    match last_line {
-      Some(last_line) => {
-        let last_line = last_line.lock().unwrap();
-        return Some(last_line.clone());
+  pub fn set_disk(&self, disk: Option<DiskCache>) {
+    *self.disk.write().unwrap() = disk.map(Arc::new);
+  }
+
+  fn disk(&self) -> Option<Arc<DiskCache>> {
+    self.disk.read().unwrap().clone()
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    self.inner.lock().unwrap().stats.clone()
+  }
+
+  pub async fn get_cached_result(&self, doc_params: &DocParams, settings: &CompletionSettings) -> Option<CopilotCompletionResponse> {
+    if let Some(result) = self.get_memory_result(doc_params, settings) {
+      self.inner.lock().unwrap().stats.hits += 1;
+      return Some(result);
+    }
+    // file io is kept off the async runtime
+    let from_disk = match self.disk() {
+      Some(disk) => {
+        let key = disk_cache::prompt_key(doc_params, settings);
+        tokio::task::spawn_blocking(move || disk.get(&key)).await.ok().flatten()
+      },
+      None => None,
+    };
+    let mut inner = self.inner.lock().unwrap();
+    match from_disk {
+      Some(response) => {
+        inner.stats.disk_hits += 1;
+        drop(inner);
+        self.set_memory_result(doc_params, settings, &response);
+        Some(response)
       },
-      None => None
+      None => {
+        inner.stats.misses += 1;
+        None
+      }
     }
   }
 
-  fn get_cached_response(&self, uri: &String, lnum: u32) -> Option<CopilotCompletionResponse> {
-    let inner = self.inner.read().unwrap();
-    let cache = inner.get(uri);
-    match cache {
-      Some(completion_response) => {
-        let completion_response = completion_response.lock().unwrap();
-        return Some(completion_response.clone());
-      },
-      None => None
+  fn get_memory_result(&self, doc_params: &DocParams, settings: &CompletionSettings) -> Option<CopilotCompletionResponse> {
+    let mut inner = self.inner.lock().unwrap();
+    let key = prompt_key(doc_params, settings);
+    // the longest matching line wins, it needs the least trimming
+    let entry = inner.entries.get(&key)?.iter()
+      .filter(|entry| doc_params.line_before.starts_with(&entry.line_before))
+      .max_by_key(|entry| entry.line_before.len())?;
+    let typed = &doc_params.line_before[entry.line_before.len()..];
+    let result = type_through(&entry.response, typed, doc_params)?;
+    let line_before = entry.line_before.to_string();
+    inner.touch(key, &line_before);
+    Some(result)
+  }
+
+  pub fn set_cached_result(&self, doc_params: &DocParams, settings: &CompletionSettings, completion_response: &CopilotCompletionResponse) {
+    self.set_memory_result(doc_params, settings, completion_response);
+    // written in the background, nothing needs to wait for it
+    if let Some(disk) = self.disk() {
+      let key = disk_cache::prompt_key(doc_params, settings);
+      let response = completion_response.clone();
+      tokio::task::spawn_blocking(move || disk.set(&key, &response));
     }
   }
 
-  fn set_file_cache(&self, uri: &String, completion_response: CopilotCompletionResponse) {
-    let mut inner = self.inner.write().unwrap();
-    inner.insert(uri.clone(), Mutex::new(completion_response));
+  fn set_memory_result(&self, doc_params: &DocParams, settings: &CompletionSettings, completion_response: &CopilotCompletionResponse) {
+    let mut inner = self.inner.lock().unwrap();
+    let key = prompt_key(doc_params, settings);
+    inner.remove_where(|entry| entry.key == key && entry.line_before == doc_params.line_before);
+    let last_used = inner.next_tick();
+    inner.insert(CacheEntry {
+      key,
+      uri: doc_params.uri.to_string(),
+      line_before: doc_params.line_before.to_string(),
+      response: completion_response.clone(),
+      size: entry_size(&doc_params.line_before, completion_response),
+      last_used,
+    });
+    inner.evict_to_limits();
   }
 
-  fn set_last_line(&self, uri: &String, last_line: u32) {
-    let mut inner = self.last_line.write().unwrap();
-    inner.insert(uri.clone(), Mutex::new(last_line));
+  /// Drops everything cached for a document, used when it is closed.
+  /// These don't count as evictions, which only report entries pushed out by the limits.
+  pub fn evict_document(&self, uri: &str) {
+    self.inner.lock().unwrap().remove_where(|entry| entry.uri == uri);
   }
+}
 
-  pub fn get_cached_result(&self, uri: &String, last_line: u32) -> Option<CopilotCompletionResponse> {
-    let cached_line = self.get_last_line(uri);
-    if cached_line.is_none() || cached_line.unwrap() != last_line {
-      return None;
-    }
-    self.get_cached_response(uri, last_line)
-  }
-
-  pub fn set_cached_result(&self, uri: &String, lnum: &u32, completion_response: &CopilotCompletionResponse) -> Option<CopilotCompletionResponse> {
-    self.set_file_cache(uri, completion_response.clone());
-    self.set_last_line(uri, lnum.clone());
-    let inner = self.inner.write().unwrap();
-    let cache = inner.get(uri);
-    match cache {
-      Some(completion_response) => {
-        let completion_response = completion_response.lock().unwrap();
-        return Some(completion_response.clone());
-      },
-      None => None
-    }
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::ProviderKind;
+  use crate::copilot::CopilotCyclingCompletion;
+  use crate::parse::PositionEncoding;
+
+
+  fn doc_params(uri: &str, line_before: &str) -> DocParams {
+    DocParams { uri: uri.to_string(), ..DocParams::from_text(&format!("fn main() {{\n{}", line_before), "\n}") }
+  }
+
+  fn response(doc_params: &DocParams, texts: &[&str]) -> CopilotCompletionResponse {
+    CopilotCompletionResponse::from_str_vec(
+      texts.iter().map(|t| t.to_string()).collect(),
+      doc_params.line_before.to_string(),
+      doc_params.pos,
+      PositionEncoding::Utf16
+    )
+  }
+
+  #[test]
+  fn types_through_matching_completions() {
+    let first = doc_params("file:///a.rs", "  let ");
+    let cached = response(&first, &["x = 1;", "xs = vec![];", "y = 2;"]);
+    let typed = doc_params("file:///a.rs", "  let x");
+
+    let through = type_through(&cached, "x", &typed).unwrap();
+    let rest: Vec<_> = through.completions.iter().map(|c| c.display_text.as_str()).collect();
+    assert_eq!(rest, vec![" = 1;", "s = vec![];"]);
+    assert!(through.completions.iter().all(|c| c.position == typed.pos && c.text.starts_with("  let x")));
+
+    // a completion typed out in full has nothing left to suggest
+    assert!(type_through(&cached, "x = 1;", &typed).is_none());
+    assert!(type_through(&cached, "z", &typed).is_none());
+  }
+
+  #[tokio::test]
+  async fn reuses_completions_while_typing_through() {
+    let cache = CopilotCache::new();
+    let first = doc_params("file:///a.rs", "  let ");
+    cache.set_cached_result(&first, &CompletionSettings::default(), &response(&first, &["x = 1;", "y = 2;"]));
+
+    let typed = doc_params("file:///a.rs", "  let x");
+    let cached = cache.get_cached_result(&typed, &CompletionSettings::default()).await.unwrap();
+    assert_eq!(cached.completions.len(), 1);
+    assert_eq!(cached.completions[0].display_text, " = 1;");
+    assert_eq!(cached.completions[0].text, "  let x = 1;");
+    assert_eq!(cached.completions[0].position, typed.pos);
+
+    assert!(cache.get_cached_result(&doc_params("file:///a.rs", "  let z"), &CompletionSettings::default()).await.is_none());
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+  }
+
+  #[tokio::test]
+  async fn misses_after_settings_change() {
+    let cache = CopilotCache::new();
+    let params = doc_params("file:///a.rs", "  let ");
+    let settings = CompletionSettings::default();
+    cache.set_cached_result(&params, &settings, &response(&params, &["x = 1;"]));
+
+    assert!(cache.get_cached_result(&params, &settings).await.is_some());
+    let colder = CompletionSettings { temperature: 0.2, ..settings.clone() };
+    assert!(cache.get_cached_result(&params, &colder).await.is_none());
+    let local = CompletionSettings { provider: ProviderKind::Local, ..settings };
+    assert!(cache.get_cached_result(&params, &local).await.is_none());
+  }
+
+  #[tokio::test]
+  async fn evicts_least_recently_used_entries() {
+    let cache = CopilotCache::with_limits(CacheLimits { max_entries: 2, max_bytes: usize::MAX });
+    let lines = ["a", "b", "c"].map(|l| doc_params("file:///a.rs", &format!("{} ", l)));
+    cache.set_cached_result(&lines[0], &CompletionSettings::default(), &response(&lines[0], &["one"]));
+    cache.set_cached_result(&lines[1], &CompletionSettings::default(), &response(&lines[1], &["two"]));
+    assert!(cache.get_cached_result(&lines[0], &CompletionSettings::default()).await.is_some());
+    cache.set_cached_result(&lines[2], &CompletionSettings::default(), &response(&lines[2], &["three"]));
+
+    assert!(cache.get_cached_result(&lines[0], &CompletionSettings::default()).await.is_some());
+    assert!(cache.get_cached_result(&lines[1], &CompletionSettings::default()).await.is_none());
+    assert_eq!(cache.stats().evictions, 1);
+    assert_eq!(cache.stats().entries, 2);
+  }
+
+  #[tokio::test]
+  async fn evicts_closed_documents() {
+    let cache = CopilotCache::new();
+    let a = doc_params("file:///a.rs", "x");
+    let b = doc_params("file:///b.rs", "y");
+    cache.set_cached_result(&a, &CompletionSettings::default(), &response(&a, &["1"]));
+    cache.set_cached_result(&b, &CompletionSettings::default(), &response(&b, &["2"]));
+    cache.evict_document("file:///a.rs");
+
+    assert!(cache.get_cached_result(&a, &CompletionSettings::default()).await.is_none());
+    assert!(cache.get_cached_result(&b, &CompletionSettings::default()).await.is_some());
+    assert_eq!(cache.stats().entries, 1);
+    assert_eq!(cache.stats().evictions, 0);
   }
 }
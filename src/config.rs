@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::error::{CopilotError, CopilotResult};
+
+// settings sent through workspace/didChangeConfiguration may be nested under this section
+pub const SECTION: &str = "copilot-rs";
+
+/// Which backend serves completions.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+  #[default]
+  Copilot,
+  /// Any server implementing OpenAI's `/v1/completions`.
+  OpenAi,
+  /// An Ollama style `/api/generate` server, e.g. Ollama running a llama.cpp model.
+  Local,
+}
+
+impl ProviderKind {
+  pub fn default_endpoint(&self) -> &'static str {
+    match self {
+      Self::Copilot => "https://copilot-proxy.githubusercontent.com/v1/engines",
+      Self::OpenAi => "https://api.openai.com/v1",
+      Self::Local => "http://localhost:11434",
+    }
+  }
+}
+
+/// Where completions are requested from and how they are sampled.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CompletionSettings {
+  pub provider: ProviderKind,
+  /// Base url, defaults to the provider's public endpoint.
+  pub endpoint: Option<String>,
+  /// Sent as a bearer token by the openai provider, `OPENAI_API_KEY` is used when unset.
+  pub api_key: Option<String>,
+  pub model: String,
+  pub max_tokens: i32,
+  pub temperature: f32,
+  pub top_p: f32,
+  pub n: i16,
+  pub stop: Vec<String>,
+  /// Token budgets the prompt and suffix are trimmed to, whole lines furthest from the cursor go first.
+  pub max_prompt_tokens: usize,
+  pub max_suffix_tokens: usize,
+  /// Part of the prompt budget given to snippets from other open documents, 0 disables them.
+  pub max_snippet_tokens: usize,
+  /// Cut multi-line completions where they dedent out of the block at the cursor.
+  pub trim_by_indentation: bool,
+}
+
+impl Default for CompletionSettings {
+  fn default() -> Self {
+    Self {
+      provider: ProviderKind::default(),
+      endpoint: None,
+      api_key: None,
+      model: "copilot-codex".to_string(),
+      max_tokens: 500,
+      temperature: 1.0,
+      top_p: 1.0,
+      n: 3,
+      stop: vec![],
+      max_prompt_tokens: 1536,
+      max_suffix_tokens: 512,
+      max_snippet_tokens: 512,
+      trim_by_indentation: true,
+    }
+  }
+}
+
+impl CompletionSettings {
+  pub fn endpoint(&self) -> &str {
+    self.endpoint.as_deref()
+      .unwrap_or(self.provider.default_endpoint())
+      .trim_end_matches('/')
+  }
+
+  pub fn completions_url(&self) -> String {
+    match self.provider {
+      ProviderKind::Copilot => format!("{}/{}/completions", self.endpoint(), self.model),
+      ProviderKind::OpenAi => format!("{}/completions", self.endpoint()),
+      ProviderKind::Local => format!("{}/api/generate", self.endpoint()),
+    }
+  }
+
+  /// Everything that shapes a completion, cached completions are only reused under equal settings.
+  pub fn fingerprint(&self) -> String {
+    serde_json::to_string(self).unwrap_or_default()
+  }
+}
+
+/// Per language values replacing the defaults, anything unset is inherited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CompletionOverrides {
+  pub provider: Option<ProviderKind>,
+  pub endpoint: Option<String>,
+  pub api_key: Option<String>,
+  pub model: Option<String>,
+  pub max_tokens: Option<i32>,
+  pub temperature: Option<f32>,
+  pub top_p: Option<f32>,
+  pub n: Option<i16>,
+  pub stop: Option<Vec<String>>,
+  pub max_prompt_tokens: Option<usize>,
+  pub max_suffix_tokens: Option<usize>,
+  pub max_snippet_tokens: Option<usize>,
+  pub trim_by_indentation: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+  #[serde(flatten)]
+  pub completion: CompletionSettings,
+  pub languages: HashMap<String, CompletionOverrides>,
+  /// GitHub Enterprise hosts whose remotes name the repository, like github.com ones do.
+  pub ghe_hosts: Vec<String>,
+}
+
+// objects are merged key by key, anything else replaces what was there
+fn merge(base: &mut Value, patch: &Value) {
+  match (base, patch) {
+    (Value::Object(base), Value::Object(patch)) => {
+      for (key, value) in patch {
+        merge(base.entry(key.to_string()).or_insert(Value::Null), value);
+      }
+    },
+    (base, patch) => *base = patch.clone(),
+  }
+}
+
+/// Our settings within a settings object, either bare or nested under the `copilot-rs` section.
+pub fn section(value: &Value) -> &Value {
+  value.get(SECTION).unwrap_or(value)
+}
+
+impl Config {
+  pub fn from_value(value: &Value) -> CopilotResult<Self> {
+    serde_json::from_value(section(value).clone()).map_err(|e| CopilotError::Config(e.to_string()))
+  }
+
+  /// This config with `value` applied on top, anything the payload leaves out keeps its current value.
+  /// Clients send `{}` or only other servers' sections after initialize, those change nothing.
+  pub fn merged(&self, value: &Value) -> CopilotResult<Self> {
+    let mut current = serde_json::to_value(self)?;
+    merge(&mut current, section(value));
+    Self::from_value(&current)
+  }
+
+  pub fn for_language(&self, language: &str) -> CompletionSettings {
+    let mut settings = self.completion.clone();
+    let Some(overrides) = self.languages.get(language) else {
+      return settings;
+    };
+    let overrides = overrides.clone();
+    if let Some(provider) = overrides.provider { settings.provider = provider; }
+    if let Some(endpoint) = overrides.endpoint { settings.endpoint = Some(endpoint); }
+    if let Some(api_key) = overrides.api_key { settings.api_key = Some(api_key); }
+    if let Some(model) = overrides.model { settings.model = model; }
+    if let Some(max_tokens) = overrides.max_tokens { settings.max_tokens = max_tokens; }
+    if let Some(temperature) = overrides.temperature { settings.temperature = temperature; }
+    if let Some(top_p) = overrides.top_p { settings.top_p = top_p; }
+    if let Some(n) = overrides.n { settings.n = n; }
+    if let Some(stop) = overrides.stop { settings.stop = stop; }
+    if let Some(max_prompt_tokens) = overrides.max_prompt_tokens { settings.max_prompt_tokens = max_prompt_tokens; }
+    if let Some(max_suffix_tokens) = overrides.max_suffix_tokens { settings.max_suffix_tokens = max_suffix_tokens; }
+    if let Some(max_snippet_tokens) = overrides.max_snippet_tokens { settings.max_snippet_tokens = max_snippet_tokens; }
+    if let Some(trim_by_indentation) = overrides.trim_by_indentation { settings.trim_by_indentation = trim_by_indentation; }
+    settings
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn applies_language_overrides() {
+    let config = Config::from_value(&json!({
+      "copilot-rs": {
+        "model": "gpt-3.5-turbo-instruct",
+        "temperature": 0.4,
+        "languages": { "python": { "temperature": 0.1, "n": 1 } }
+      }
+    })).unwrap();
+
+    let python = config.for_language("python");
+    assert_eq!(python.model, "gpt-3.5-turbo-instruct");
+    assert_eq!((python.temperature, python.n), (0.1, 1));
+    let rust = config.for_language("rust");
+    assert_eq!((rust.temperature, rust.n, rust.max_tokens), (0.4, 3, 500));
+    assert_eq!(rust.completions_url(), "https://copilot-proxy.githubusercontent.com/v1/engines/gpt-3.5-turbo-instruct/completions");
+  }
+
+  #[test]
+  fn builds_provider_urls() {
+    let config = Config::from_value(&json!({
+      "languages": {
+        "python": { "provider": "openai" },
+        "lua": { "provider": "local", "endpoint": "http://gpu-box:11434/" }
+      }
+    })).unwrap();
+
+    assert_eq!(config.for_language("python").completions_url(), "https://api.openai.com/v1/completions");
+    assert_eq!(config.for_language("lua").completions_url(), "http://gpu-box:11434/api/generate");
+  }
+
+  #[test]
+  fn finds_the_section_nested_or_bare() {
+    let cache = json!({ "maxEntries": 64 });
+    assert_eq!(section(&json!({ "copilot-rs": { "cache": cache.clone() } }))["cache"], cache);
+    assert_eq!(section(&json!({ "cache": cache.clone() }))["cache"], cache);
+  }
+
+  #[test]
+  fn merges_changes_over_the_current_config() {
+    let config = Config::from_value(&json!({
+      "copilot-rs": { "model": "gpt-3.5-turbo-instruct", "languages": { "python": { "n": 1 } } }
+    })).unwrap();
+    for unrelated in [json!({}), json!({ "rust-analyzer": { "checkOnSave": true } })] {
+      assert_eq!(config.merged(&unrelated).unwrap(), config);
+    }
+
+    let changed = config.merged(&json!({ "copilot-rs": { "languages": { "python": { "temperature": 0.1 } } } })).unwrap();
+    assert_eq!(changed.completion.model, "gpt-3.5-turbo-instruct");
+    let python = changed.for_language("python");
+    assert_eq!((python.n, python.temperature), (1, 0.1));
+  }
+}
@@ -1,8 +1,11 @@
-use futures_util::{StreamExt, FutureExt};
+use futures_util::{stream, Stream, StreamExt, FutureExt};
+use std::{pin::{pin, Pin}, collections::{BTreeMap, HashMap, HashSet, VecDeque}};
 use ropey::Rope;
 use eventsource_stream::Eventsource;
 use tower_lsp::lsp_types::*;
 use serde_derive::{Deserialize, Serialize};
+use crate::parse::PositionEncoding;
+use crate::language::Language;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -11,12 +14,17 @@ pub struct CopilotCyclingCompletion {
   pub text: String, // fulltext
   pub range: Range, // start char always 0
   pub position: Position,
+  /// Mean token log probability, higher is more confident. Absent when the provider sends no logprobs.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub score: Option<f32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CopilotAnswer {
   pub id: Option<String>,
+  #[serde(default)]
   pub model: String,
+  #[serde(default)]
   pub created: u128,
   pub choices: Vec<Choices>
 }
@@ -33,7 +41,17 @@ pub struct Choices {
   pub text: String,
   pub index: i16,
   pub finish_reason: Option<String>,
-  pub logprobs: Option<String>,
+  pub logprobs: Option<Logprobs>,
+}
+
+/// Per token log probabilities of the text in a chunk. The first token's is null when the prompt is echoed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Logprobs {
+  pub tokens: Vec<String>,
+  pub token_logprobs: Vec<Option<f32>>,
+  pub top_logprobs: Vec<Option<HashMap<String, f32>>>,
+  pub text_offset: Vec<usize>,
 }
 
 pub async fn on_cancel() -> CopilotCompletionResponse {
@@ -50,12 +68,40 @@ pub struct CopilotCompletionResponse {
 }
 
 impl CopilotCompletionResponse {
-  pub fn from_str_vec(str_vec: Vec<String>, line_before: String, pos: Position) -> Self {
-    let completions = str_vec.iter().map(|x| {
-      return CopilotCyclingCompletion::new(x.to_string(), line_before.to_string(), pos.clone());
-    }).collect();
+  /// Builds one completion per distinct text, in the order they were received.
+  pub fn from_str_vec(str_vec: Vec<String>, line_before: String, pos: Position, encoding: PositionEncoding) -> Self {
+    let completions = str_vec.into_iter()
+      .enumerate()
+      .map(|(index, text)| Completion { index: index as i16, text, score: None })
+      .collect();
+    Self::from_completions(completions, line_before, pos, encoding)
+  }
+
+  /// Builds one completion per distinct text, best scored first. Unscored ones keep their order after the rest.
+  pub fn from_completions(mut completions: Vec<Completion>, line_before: String, pos: Position, encoding: PositionEncoding) -> Self {
+    completions.sort_by(|a, b| match (a.score, b.score) {
+      (Some(a), Some(b)) => b.total_cmp(&a),
+      (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    let mut seen = HashSet::new();
+    let completions = completions.into_iter()
+      .filter(|x| seen.insert(x.text.trim_end().to_string()))
+      .map(|x| CopilotCyclingCompletion {
+        score: x.score,
+        ..CopilotCyclingCompletion::new(x.text, line_before.to_string(), pos, encoding)
+      })
+      .collect();
     Self { completions, cancellation_reason: None }
   }
+
+  /// Standard lsp list, incomplete because the suggestions depend on everything typed so far.
+  pub fn to_completion_list(&self) -> CompletionList {
+    let items = self.completions.iter()
+      .enumerate()
+      .map(|(i, completion)| completion.to_completion_item(i))
+      .collect();
+    CompletionList { is_incomplete: true, items }
+  }
 }
 
 fn handle_event(
@@ -71,10 +117,10 @@ fn handle_event(
 }
 
 impl CopilotCyclingCompletion {
-  pub fn new(text: String, line_before: String, position: Position) -> Self {
+  pub fn new(text: String, line_before: String, position: Position, encoding: PositionEncoding) -> Self {
     let display_text = text.clone();
     let text = format!("{}{}", line_before, text);
-    let end_char = text.find('\n').unwrap_or(text.len()) as u32;
+    let end_char = encoding.len(text.lines().next().unwrap_or_default()) as u32;
     Self {
       display_text, // partial text
       text, // fulltext
@@ -83,18 +129,53 @@ impl CopilotCyclingCompletion {
         end: Position { character: end_char, line: position.line }
       }, // start char always 0
       position,
+      score: None,
+    }
+  }
+}
+
+impl CopilotCyclingCompletion {
+  /// Standard lsp item replacing the line up to the cursor with the full completed text.
+  pub fn to_completion_item(&self, index: usize) -> CompletionItem {
+    // completions after a block opener start on the next line
+    let label = self.display_text.lines()
+      .map(str::trim)
+      .find(|line| !line.is_empty())
+      .unwrap_or_default()
+      .to_string();
+    CompletionItem {
+      label,
+      kind: Some(CompletionItemKind::TEXT),
+      detail: Some("copilot".to_string()),
+      documentation: Some(Documentation::MarkupContent(MarkupContent {
+        kind: MarkupKind::Markdown,
+        value: format!("```\n{}\n```", self.text)
+      })),
+      sort_text: Some(format!("{:04}", index)),
+      filter_text: Some(self.text.to_string()),
+      insert_text_format: Some(InsertTextFormat::PLAIN_TEXT),
+      text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+        range: Range {
+          start: Position { line: self.position.line, character: 0 },
+          end: self.position
+        },
+        new_text: self.text.to_string()
+      })),
+      ..CompletionItem::default()
     }
   }
 }
 
 fn create_item(
   text: String,
+  score: Option<f32>,
   line_before: &String,
-  position: Position
+  position: Position,
+  encoding: PositionEncoding
 ) -> CopilotCyclingCompletion {
   let display_text = text.clone();
   let text = format!("{}{}", line_before, text);
-  let end_char = text.find('\n').unwrap_or(text.len()) as u32;
+  let end_char = encoding.len(text.lines().next().unwrap_or_default()) as u32;
   CopilotCyclingCompletion {
     display_text, // partial text
     text, // fulltext
@@ -109,34 +190,174 @@ fn create_item(
       }
     }, // start char always 0
     position,
+    score,
+  }
+}
+
+/// One finished choice of a completion request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+  pub index: i16,
+  pub text: String,
+  /// Mean log probability of the choice's tokens.
+  pub score: Option<f32>,
+}
+
+#[derive(Debug, Default)]
+struct PendingChoice {
+  text: String,
+  logprob_sum: f32,
+  logprob_count: usize,
+}
+
+/// Chunks of every choice arrive interleaved on one stream.
+/// Text is collected per `index` and a choice is complete once its own `finish_reason` shows up,
+/// or as soon as its text reaches one of the stop sequences.
+#[derive(Debug, Default)]
+pub struct ChoiceAccumulator {
+  pending: HashMap<i16, PendingChoice>,
+  stops: Vec<String>,
+  // choices cut at a stop sequence, whatever the server still sends for them is ignored
+  stopped: HashSet<i16>,
+}
+
+/// Where the earliest stop sequence starts, only looking at text that could contain one ending
+/// after `from`.
+pub fn find_stop(text: &str, from: usize, stops: &[String]) -> Option<usize> {
+  let longest = stops.iter().map(String::len).max()?;
+  let mut start = from.saturating_sub(longest.saturating_sub(1)).min(text.len());
+  while !text.is_char_boundary(start) {
+    start -= 1;
+  }
+  stops.iter()
+    .filter(|stop| !stop.is_empty())
+    .filter_map(|stop| text[start..].find(stop.as_str()))
+    .min()
+    .map(|idx| start + idx)
+}
+
+impl ChoiceAccumulator {
+  pub fn with_stops(stops: Vec<String>) -> Self {
+    Self { stops, ..Self::default() }
+  }
+
+  /// Returns the full choice once this chunk finishes it.
+  pub fn push(&mut self, choice: &Choices) -> Option<Completion> {
+    if self.stopped.contains(&choice.index) {
+      return None;
+    }
+    let pending = self.pending.entry(choice.index).or_default();
+    let from = pending.text.len();
+    pending.text.push_str(&choice.text);
+    let logprobs = choice.logprobs.iter().flat_map(|logprobs| logprobs.token_logprobs.iter().flatten());
+    for logprob in logprobs {
+      pending.logprob_sum += logprob;
+      pending.logprob_count += 1;
+    }
+    let stop = find_stop(&pending.text, from, &self.stops);
+    if let Some(stop) = stop {
+      pending.text.truncate(stop);
+      self.stopped.insert(choice.index);
+    } else {
+      choice.finish_reason.as_ref()?;
+    }
+    let pending = self.pending.remove(&choice.index).unwrap_or_default();
+    let score = (pending.logprob_count > 0).then(|| pending.logprob_sum / pending.logprob_count as f32);
+    Some(Completion { index: choice.index, text: pending.text, score })
+  }
+}
+
+struct ChoiceStreamState<S> {
+  events: Pin<Box<S>>,
+  choices: ChoiceAccumulator,
+  ready: VecDeque<Result<Completion, String>>,
+  // choices still to finish, the body isn't read any further once all `n` have
+  remaining: usize,
+  done: bool,
+}
+
+/// Turns an SSE body into finished choices as they complete, errors are passed along as messages.
+/// Choices end at the first of `stops` without waiting for the server, and the stream ends once
+/// `n` choices have finished.
+pub fn choice_stream<S, B, E>(body: S, stops: Vec<String>, n: usize) -> impl Stream<Item = Result<Completion, String>>
+where
+  S: Stream<Item = Result<B, E>>,
+  B: AsRef<[u8]>,
+  E: std::fmt::Display,
+{
+  let state = ChoiceStreamState {
+    events: Box::pin(body.eventsource()),
+    choices: ChoiceAccumulator::with_stops(stops),
+    ready: VecDeque::new(),
+    remaining: n,
+    done: false,
+  };
+  stream::unfold(state, |mut state| async move {
+    loop {
+      if let Some(item) = state.ready.pop_front() {
+        return Some((item, state));
+      }
+      if state.done {
+        return None;
+      }
+      match state.events.next().await {
+        None => state.done = true,
+        Some(Err(e)) => {
+          state.ready.push_back(Err(e.to_string()));
+          state.done = true;
+        },
+        Some(Ok(event)) => match handle_event(event) {
+          CopilotResponse::Answer(ans) => {
+            let finished: Vec<Completion> = ans.choices.iter().filter_map(|x| state.choices.push(x)).collect();
+            state.remaining = state.remaining.saturating_sub(finished.len());
+            state.done = state.remaining == 0;
+            state.ready.extend(finished.into_iter().map(Ok));
+          },
+          CopilotResponse::Done => state.done = true,
+          CopilotResponse::Error(e) => state.ready.push_back(Err(e)),
+        }
+      }
+    }
+  })
+}
+
+/// Drains a completion stream, returning the choices ordered by index and the last error seen.
+pub async fn collect_completions<S>(completions: S) -> (Vec<Completion>, Option<String>)
+where
+  S: Stream<Item = Result<Completion, String>>,
+{
+  let mut completions = pin!(completions);
+  let mut finished = BTreeMap::new();
+  let mut cancellation_reason = None;
+  while let Some(completion) = completions.next().await {
+    match completion {
+      Ok(completion) => { finished.insert(completion.index, completion); },
+      Err(e) => { cancellation_reason = Some(e) }
+    }
   }
+  (finished.into_values().collect(), cancellation_reason)
+}
+
+/// Reads an SSE body to the end, returning the finished choices and the last error seen.
+pub async fn collect_choices<S, B, E>(body: S) -> (Vec<Completion>, Option<String>)
+where
+  S: Stream<Item = Result<B, E>>,
+  B: AsRef<[u8]>,
+  E: std::fmt::Display,
+{
+  collect_completions(choice_stream(body, vec![], usize::MAX)).await
 }
 
 pub async fn fetch_completions(
   resp: reqwest::Response,
   line_before: String,
   position: Position,
+  encoding: PositionEncoding,
 ) -> Result<CopilotCompletionResponse, String> {
-  let mut stream = resp.bytes_stream().eventsource();
-  let mut completion_list = Vec::<CopilotCyclingCompletion>::with_capacity(4);
-  let mut s = "".to_string();
-  let mut cancellation_reason = None;
-  while let Some(event) = stream.next().await {
-    match handle_event(event.unwrap()) {
-      CopilotResponse::Answer(ans) => {
-        ans.choices.iter().for_each(|x| {
-          s.push_str(&x.text);
-          if x.finish_reason.is_some() {
-            let item = create_item(s.clone(), &line_before, position);
-            completion_list.push(item);
-            s = "".to_string();
-          }
-        });
-      },
-      CopilotResponse::Done => { break; },
-      CopilotResponse::Error(e) => { cancellation_reason = Some(e) }
-    }
-  }
+  let (choices, cancellation_reason) = collect_choices(resp.bytes_stream()).await;
+  let completion_list = choices.into_iter()
+    .map(|choice| create_item(choice.text, choice.score, &line_before, position, encoding))
+    .collect();
   Ok(
     CopilotCompletionResponse {
       cancellation_reason,
@@ -197,6 +418,182 @@ impl CopilotEditorInfo {
   }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LexState {
+  Code,
+  Str(&'static str),
+  Escape(&'static str),
+  LineComment,
+  BlockComment(&'static str),
+}
+
+/// Follows brackets through code, skipping comments and string literals.
+#[derive(Debug, Clone)]
+struct BracketLexer {
+  language: &'static Language,
+  state: LexState,
+}
+
+// length of a character literal like 'x', '\n' or '\u{1F600}' at the start of `rest`,
+// a lifetime like 'a has no closing quote right after its first character
+fn char_literal_len(rest: &str) -> Option<usize> {
+  let mut chars = rest.char_indices().skip(1);
+  match chars.next()? {
+    (_, '\\') => {
+      chars.next()?;
+      chars.take(10).find(|&(_, c)| c == '\'' || c == '\n')
+        .filter(|&(_, c)| c == '\'')
+        .map(|(i, _)| i + 1)
+    },
+    (_, '\'' | '\n') => None,
+    _ => chars.next().filter(|&(_, c)| c == '\'').map(|(i, _)| i + 1),
+  }
+}
+
+fn closing(open: char) -> char {
+  match open {
+    '(' => ')',
+    '[' => ']',
+    _ => '}',
+  }
+}
+
+impl BracketLexer {
+  fn new(language: &'static Language) -> Self {
+    Self { language, state: LexState::Code }
+  }
+
+  /// Consumes the start of `rest`, returning how many bytes were read and the bracket among them.
+  fn step(&mut self, rest: &str) -> (usize, Option<char>) {
+    let c = rest.chars().next().unwrap_or_default();
+    match self.state {
+      LexState::Code => {
+        if let Some((start, end)) = self.language.block_comment().filter(|(start, _)| rest.starts_with(start)) {
+          self.state = LexState::BlockComment(end);
+          return (start.len(), None);
+        }
+        if let Some(start) = self.language.line_comment().filter(|start| rest.starts_with(start)) {
+          self.state = LexState::LineComment;
+          return (start.len(), None);
+        }
+        if let Some(quote) = self.language.quotes.iter().find(|quote| rest.starts_with(*quote)) {
+          self.state = LexState::Str(quote);
+          return (quote.len(), None);
+        }
+        if let Some(len) = Some(c).filter(|&c| c == '\'' && self.language.char_literals).and_then(|_| char_literal_len(rest)) {
+          return (len, None);
+        }
+        let bracket = matches!(c, '(' | '[' | '{' | ')' | ']' | '}');
+        return (c.len_utf8(), bracket.then_some(c));
+      },
+      LexState::BlockComment(end) if rest.starts_with(end) => {
+        self.state = LexState::Code;
+        return (end.len(), None);
+      },
+      LexState::BlockComment(_) => {},
+      LexState::LineComment if c == '\n' => self.state = LexState::Code,
+      LexState::LineComment => {},
+      LexState::Escape(quote) => self.state = LexState::Str(quote),
+      LexState::Str(quote) if c == '\\' => self.state = LexState::Escape(quote),
+      LexState::Str(quote) if rest.starts_with(quote) => {
+        self.state = LexState::Code;
+        return (quote.len(), None);
+      },
+      // unterminated literals end with the line, except template and triple quoted strings
+      LexState::Str(quote) if c == '\n' && quote != "`" && quote.len() == 1 => self.state = LexState::Code,
+      LexState::Str(_) => {},
+    }
+    (c.len_utf8(), None)
+  }
+
+  /// Brackets left open at the end of `text`, mismatched closers are ignored.
+  fn open_brackets(&mut self, text: &str) -> Vec<char> {
+    let mut open = vec![];
+    let mut i = 0;
+    while i < text.len() {
+      let (len, bracket) = self.step(&text[i..]);
+      match bracket {
+        Some(c @ ('(' | '[' | '{')) => open.push(c),
+        Some(c) if open.last().map(|&o| closing(o)) == Some(c) => { open.pop(); },
+        _ => {},
+      }
+      i += len;
+    }
+    open
+  }
+
+  /// How many brackets opened before `text` it closes.
+  fn outer_closers(&mut self, text: &str) -> usize {
+    let mut depth = 0;
+    let mut closers = 0;
+    let mut i = 0;
+    while i < text.len() {
+      let (len, bracket) = self.step(&text[i..]);
+      match bracket {
+        Some('(' | '[' | '{') => depth += 1,
+        Some(_) if depth == 0 => closers += 1,
+        Some(_) => depth -= 1,
+        None => {},
+      }
+      i += len;
+    }
+    closers
+  }
+}
+
+/// Trims a multi-line completion so that it leaves the brackets of the document as balanced as it found them.
+/// The completion is cut before a closer that mismatches or that closes a bracket the suffix already closes,
+/// or back to its last balanced line when it leaves brackets or a literal open.
+/// `None` means no part of it can be kept.
+pub fn balance_completion(completion: &str, prefix: &str, suffix: &str, language: &'static Language) -> Option<String> {
+  if !completion.trim().contains('\n') {
+    return Some(completion.to_string());
+  }
+  let mut lexer = BracketLexer::new(language);
+  let mut outer = lexer.open_brackets(prefix);
+  let start = lexer.state;
+  let closed_by_suffix = lexer.clone().outer_closers(suffix);
+  let mut closable = outer.len().saturating_sub(closed_by_suffix);
+  let settled = |state: LexState| state == start || matches!(state, LexState::Code | LexState::LineComment);
+
+  let mut open: Vec<char> = vec![];
+  let mut balanced_end = 0;
+  let mut i = 0;
+  while i < completion.len() {
+    let rest = &completion[i..];
+    if rest.starts_with('\n') && open.is_empty() && settled(lexer.state) {
+      balanced_end = i;
+    }
+    let (len, bracket) = lexer.step(rest);
+    match bracket {
+      Some(c @ ('(' | '[' | '{')) => open.push(c),
+      Some(c) if !open.is_empty() => {
+        if open.pop().map(closing) != Some(c) {
+          return cut_completion(completion, balanced_end);
+        }
+      },
+      Some(c) => {
+        if closable == 0 || outer.pop().map(closing) != Some(c) {
+          return cut_completion(completion, i);
+        }
+        closable -= 1;
+      },
+      None => {},
+    }
+    i += len;
+  }
+  if open.is_empty() && settled(lexer.state) {
+    return Some(completion.to_string());
+  }
+  cut_completion(completion, balanced_end)
+}
+
+fn cut_completion(completion: &str, end: usize) -> Option<String> {
+  let kept = completion[..end].trim_end();
+  (!kept.trim().is_empty()).then(|| kept.to_string())
+}
+
+#[derive(Clone)]
 pub struct DocParams {
   pub rope: Rope,
   pub uri: String,
@@ -204,5 +601,200 @@ pub struct DocParams {
   pub language: String,
   pub line_before: String,
   pub prefix: String,
-  pub suffix: String
+  pub suffix: String,
+  /// `owner/name` of the repository the document lives in.
+  pub nwo: Option<String>,
+  /// Indentation of the first non-blank line after the cursor.
+  pub next_indent: usize
+}
+
+#[cfg(test)]
+impl DocParams {
+  /// A rust document with the cursor between `prefix` and `suffix`.
+  pub fn from_text(prefix: &str, suffix: &str) -> Self {
+    let line_before = prefix.rsplit('\n').next().unwrap_or_default().to_string();
+    DocParams {
+      rope: Rope::from_str(&format!("{}{}", prefix, suffix)),
+      uri: "file:///main.rs".to_string(),
+      pos: Position::new(prefix.matches('\n').count() as u32, line_before.encode_utf16().count() as u32),
+      language: "rust".to_string(),
+      line_before,
+      prefix: prefix.to_string(),
+      suffix: suffix.to_string(),
+      nwo: None,
+      next_indent: 0,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bytes::Bytes;
+  use futures::stream;
+
+  fn frame(index: i16, text: &str, finish_reason: Option<&str>) -> String {
+    let answer = serde_json::json!({
+      "id": "cmpl",
+      "model": "copilot-codex",
+      "created": 0,
+      "choices": [{ "text": text, "index": index, "finish_reason": finish_reason, "logprobs": null }]
+    });
+    format!("data: {}\n\n", answer)
+  }
+
+  async fn collect(frames: Vec<String>) -> (Vec<String>, Option<String>) {
+    let chunks = frames.into_iter().map(|f| Ok::<_, std::io::Error>(Bytes::from(f)));
+    let (choices, reason) = collect_choices(stream::iter(chunks)).await;
+    (choices.into_iter().map(|choice| choice.text).collect(), reason)
+  }
+
+  #[test]
+  fn builds_completion_items() {
+    let position = Position::new(3, 6);
+    let response = CopilotCompletionResponse::from_str_vec(
+      vec!["\n    body();\n}".to_string(), "ment".to_string()],
+      "é.ele".to_string(),
+      position,
+      PositionEncoding::Utf8
+    );
+    let list = response.to_completion_list();
+    assert!(list.is_incomplete);
+    assert_eq!(list.items[0].label, "body();");
+    assert_eq!(list.items[1].label, "ment");
+
+    // the edit ends at the cursor as the client counts it, "é" is two utf-8 units
+    let Some(CompletionTextEdit::Edit(edit)) = &list.items[1].text_edit else { panic!("no text edit") };
+    assert_eq!(edit.range, Range::new(Position::new(3, 0), position));
+    assert_eq!(edit.new_text, "é.element");
+    assert_eq!(response.completions[1].range.end, Position::new(3, 10));
+  }
+
+  #[tokio::test]
+  async fn demultiplexes_interleaved_choices() {
+    let (choices, reason) = collect(vec![
+      frame(0, "fn ", None),
+      frame(2, "let ", None),
+      frame(1, "struct ", None),
+      frame(0, "main()", None),
+      frame(2, "x = 1;", Some("stop")),
+      frame(1, "Foo;", Some("stop")),
+      frame(0, " {}", Some("stop")),
+      "data: [DONE]\n\n".to_string(),
+    ]).await;
+    assert_eq!(choices, vec!["fn main() {}", "struct Foo;", "let x = 1;"]);
+    assert_eq!(reason, None);
+  }
+
+  #[tokio::test]
+  async fn finishes_each_choice_independently() {
+    let (choices, _) = collect(vec![
+      frame(1, "done early", Some("stop")),
+      frame(0, "still ", None),
+      frame(0, "going", None),
+      "data: [DONE]\n\n".to_string(),
+    ]).await;
+    // choice 0 never finished before the stream ended
+    assert_eq!(choices, vec!["done early"]);
+  }
+
+  #[tokio::test]
+  async fn stops_reading_at_stop_sequences() {
+    let frames = vec![
+      frame(0, "a + ", None),
+      frame(1, "b\n", None),
+      frame(0, "b\n", None),
+      frame(1, "never read", None),
+    ];
+    let chunks = frames.into_iter().map(|f| Ok::<_, std::io::Error>(Bytes::from(f)));
+    // the body never ends, both choices finish on the stop sequence alone
+    let body = stream::iter(chunks).chain(stream::pending());
+    let (choices, reason) = collect_completions(choice_stream(body, vec!["\n".to_string()], 2)).await;
+    let texts: Vec<String> = choices.into_iter().map(|choice| choice.text).collect();
+    assert_eq!(texts, vec!["a + b", "b"]);
+    assert_eq!(reason, None);
+    assert_eq!(find_stop("abc\n\ndef", 4, &["\n\n".to_string()]), Some(3));
+  }
+
+  #[tokio::test]
+  async fn handles_frames_split_across_chunks() {
+    let whole = format!("{}{}", frame(0, "a", None), frame(0, "b", Some("length")));
+    let (head, tail) = whole.split_at(whole.len() / 2);
+    let (choices, _) = collect(vec![head.to_string(), tail.to_string()]).await;
+    assert_eq!(choices, vec!["ab"]);
+  }
+
+  #[tokio::test]
+  async fn ranks_choices_by_mean_logprob() {
+    let answer = |index: i16, text: &str, logprobs: &[f32]| format!("data: {}\n\n", serde_json::json!({
+      "choices": [{
+        "text": text,
+        "index": index,
+        "finish_reason": "stop",
+        "logprobs": { "tokens": [text], "token_logprobs": logprobs, "top_logprobs": [], "text_offset": [0] }
+      }]
+    }));
+    let chunks = vec![answer(0, "unlikely", &[-3.0, -1.0]), answer(1, "likely", &[-0.5, -0.25]), frame(2, "unscored", Some("stop"))];
+    let (choices, _) = collect_choices(stream::iter(chunks.into_iter().map(|f| Ok::<_, std::io::Error>(Bytes::from(f))))).await;
+    assert_eq!(choices[0].score, Some(-2.0));
+
+    let response = CopilotCompletionResponse::from_completions(choices, String::new(), Position::new(0, 0), PositionEncoding::Utf16);
+    let ranked: Vec<(&str, Option<f32>)> = response.completions.iter().map(|c| (c.text.as_str(), c.score)).collect();
+    assert_eq!(ranked, vec![("likely", Some(-0.375)), ("unlikely", Some(-2.0)), ("unscored", None)]);
+  }
+
+  #[test]
+  fn drops_duplicate_completions() {
+    let texts = vec!["a()".to_string(), "b()".to_string(), "a()\n".to_string()];
+    let response = CopilotCompletionResponse::from_str_vec(texts, "  ".to_string(), Position::new(0, 2), PositionEncoding::Utf16);
+    let texts: Vec<&str> = response.completions.iter().map(|c| c.text.as_str()).collect();
+    assert_eq!(texts, vec!["  a()", "  b()"]);
+  }
+
+  #[test]
+  fn balances_multi_line_completions() {
+    let rust = Language::find("rust").unwrap();
+    let prefix = "fn main() {\n    ";
+    // the suffix already closes main
+    assert_eq!(
+      balance_completion("let x = 1;\n    println!(\"{}\", x);\n}\n\nfn other() {}", prefix, "\n}\n", rust).as_deref(),
+      Some("let x = 1;\n    println!(\"{}\", x);")
+    );
+    // nothing closes it yet
+    let whole = "let x = 1;\n}";
+    assert_eq!(balance_completion(whole, prefix, "\n\nfn other() {}", rust).as_deref(), Some(whole));
+    // brackets in comments and strings don't count, an unclosed call is cut back to the last balanced line
+    assert_eq!(
+      balance_completion("let s = \"(\"; // {\n    foo(a,\n        b", prefix, "\n}", rust).as_deref(),
+      Some("let s = \"(\"; // {")
+    );
+    assert_eq!(balance_completion("foo(\n    a,", prefix, "\n}", rust), None);
+    assert_eq!(balance_completion("x)", "foo(", ")", rust).as_deref(), Some("x)"));
+
+    // brackets inside a docstring spanning lines don't count
+    let python = Language::find("python").unwrap();
+    let docstring = "\"\"\"\n    Returns (x\n    \"\"\"\n    return 1";
+    assert_eq!(balance_completion(docstring, "def f():\n    ", "", python).as_deref(), Some(docstring));
+
+    // rust character literals hold brackets, lifetimes are no literals at all
+    let chars = "if c == '{' || c == '\\'' {\n        depth += 1;\n    }";
+    assert_eq!(balance_completion(chars, prefix, "\n}", rust).as_deref(), Some(chars));
+    let lifetimes = "fn f<'a>(x: &'a str) {\n    }";
+    assert_eq!(balance_completion(lifetimes, prefix, "\n}", rust).as_deref(), Some(lifetimes));
+
+    // f# type parameters are no strings
+    let fsharp = Language::find("fsharp").unwrap();
+    let generic = "let f (x: 'T) =\n    [x]";
+    assert_eq!(balance_completion(generic, "", "", fsharp).as_deref(), Some(generic));
+  }
+
+  #[tokio::test]
+  async fn reports_malformed_frames() {
+    let (choices, reason) = collect(vec![
+      "data: {not json}\n\n".to_string(),
+      frame(0, "ok", Some("stop")),
+    ]).await;
+    assert_eq!(choices, vec!["ok"]);
+    assert!(reason.is_some());
+  }
 }
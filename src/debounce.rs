@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::{Mutex, MutexGuard};
 use std::marker::PhantomData;
 use tokio::runtime;
@@ -19,42 +20,72 @@ impl Runner {
       delay
     }
   }
+  /// How long requests wait for newer ones, retries of a request fit in the same time.
+  pub fn delay(&self) -> tokio::time::Duration {
+    self.delay
+  }
+
   fn set_id(&self, new_id: i32) -> i32 {
     let mut lock = self.id.write().unwrap();
     *lock = new_id;
     *lock
   }
 
-  pub async fn increment_and_do_stuff(&self) -> bool {
-    let id = self.inc.increment();
-    self.foo(id).await
+  /// Waits out the delay, true when no newer request for the same `key` came in meanwhile.
+  pub async fn increment_and_do_stuff(&self, key: &'static str) -> bool {
+    let id = self.inc.increment(key);
+    self.foo(key, id).await
   }
 
-  async fn foo(&self, id: i32) -> bool {
+  // invalidates anything under `key` still waiting out the delay
+  pub fn increment(&self, key: &'static str) -> i32 {
+    self.inc.increment(key)
+  }
+
+  async fn foo(&self, key: &'static str, id: i32) -> bool {
     tokio::time::sleep(self.delay).await;
-    let current_id = self.inc.read();
+    let current_id = self.inc.read(key);
     id == current_id
   }
 }
 
+// one counter per key, requests only supersede others with the same key
 #[derive(Debug)]
 struct CanIncrement {
-  mutex: RwLock<i32>,
+  mutex: RwLock<HashMap<&'static str, i32>>,
 }
 
 impl CanIncrement {
   fn new() -> Self {
-    Self { mutex: RwLock::new(0) }
+    Self { mutex: RwLock::new(HashMap::new()) }
   }
   // This function is not marked async.
-  fn increment(&self) -> i32 {
+  fn increment(&self, key: &'static str) -> i32 {
     let mut lock = self.mutex.write().unwrap();
-    *lock += 1;
-    *lock
+    let id = lock.entry(key).or_insert(0);
+    *id += 1;
+    *id
   }
-  fn read(&self) -> i32 {
+  fn read(&self, key: &'static str) -> i32 {
     let lock = self.mutex.read().unwrap();
-    *lock
+    lock.get(key).copied().unwrap_or(0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn supersedes_only_the_same_key() {
+    let runner = Runner::new(tokio::time::Duration::from_millis(10));
+    let (completion, inline, newer) = tokio::join!(
+      runner.increment_and_do_stuff("textDocument/completion"),
+      runner.increment_and_do_stuff("textDocument/inlineCompletion"),
+      async { runner.increment("textDocument/inlineCompletion") }
+    );
+    assert!(completion && !inline);
+    assert_eq!(newer, 2);
   }
 }
 
@@ -0,0 +1,189 @@
+use std::{
+  fs,
+  io,
+  path::PathBuf,
+  sync::atomic::{AtomicU32, Ordering},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use serde_derive::{Deserialize, Serialize};
+use sha256::digest;
+use uuid::Uuid;
+use crate::config::CompletionSettings;
+use crate::copilot::{CopilotCompletionResponse, DocParams};
+
+// pruning lists the whole directory, so only do it every so often
+const PRUNE_EVERY: u32 = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DiskCacheOptions {
+  pub enabled: bool,
+  pub path: Option<String>,
+  pub ttl_secs: u64,
+  pub max_bytes: u64,
+}
+
+impl Default for DiskCacheOptions {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      path: None,
+      ttl_secs: 7 * 24 * 60 * 60,
+      max_bytes: 64 * 1024 * 1024,
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskEntry {
+  created: u64,
+  response: CopilotCompletionResponse,
+}
+
+/// Completions persisted across restarts, one json file per prompt.
+/// Files are written to a temporary name and renamed into place,
+/// so several server instances can share the directory without locking.
+#[derive(Debug)]
+pub struct DiskCache {
+  dir: PathBuf,
+  ttl: Duration,
+  max_bytes: u64,
+  writes: AtomicU32,
+}
+
+/// `$XDG_CACHE_HOME/copilot-rs`, falling back to `~/.cache/copilot-rs`.
+pub fn default_dir() -> PathBuf {
+  let base = std::env::var_os("XDG_CACHE_HOME")
+    .filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .unwrap_or_else(|| PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".cache"));
+  base.join("copilot-rs")
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Stable across processes, unlike the std hasher used by the in-memory layer.
+pub fn prompt_key(doc_params: &DocParams, settings: &CompletionSettings) -> String {
+  digest(format!(
+    "{}\0{}\0{}\0{}",
+    settings.fingerprint(),
+    doc_params.language,
+    doc_params.prefix,
+    doc_params.suffix
+  ))
+}
+
+impl DiskCache {
+  pub fn open(options: &DiskCacheOptions) -> io::Result<Self> {
+    let dir = options.path.as_ref().map(PathBuf::from).unwrap_or_else(default_dir);
+    fs::create_dir_all(&dir)?;
+    let cache = Self {
+      dir,
+      ttl: Duration::from_secs(options.ttl_secs),
+      max_bytes: options.max_bytes,
+      writes: AtomicU32::new(0),
+    };
+    cache.prune();
+    Ok(cache)
+  }
+
+  fn entry_path(&self, key: &str) -> PathBuf {
+    self.dir.join(format!("{}.json", key))
+  }
+
+  pub fn get(&self, key: &str) -> Option<CopilotCompletionResponse> {
+    let path = self.entry_path(key);
+    let entry = serde_json::from_slice::<DiskEntry>(&fs::read(&path).ok()?).ok()?;
+    if now().saturating_sub(entry.created) > self.ttl.as_secs() {
+      // another instance may have removed it already
+      let _ = fs::remove_file(path);
+      return None;
+    }
+    Some(entry.response)
+  }
+
+  pub fn set(&self, key: &str, response: &CopilotCompletionResponse) {
+    let entry = DiskEntry { created: now(), response: response.clone() };
+    let Ok(contents) = serde_json::to_vec(&entry) else { return };
+    let tmp = self.dir.join(format!("{}.{}.tmp", key, Uuid::new_v4()));
+    let written = fs::write(&tmp, contents).and_then(|_| fs::rename(&tmp, self.entry_path(key)));
+    if written.is_err() {
+      let _ = fs::remove_file(tmp);
+    }
+    if self.writes.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1 {
+      self.prune();
+    }
+  }
+
+  /// Removes expired entries, then the oldest ones until the directory fits in `max_bytes`.
+  pub fn prune(&self) {
+    let Ok(dir) = fs::read_dir(&self.dir) else { return };
+    let mut files: Vec<(PathBuf, SystemTime, u64)> = dir
+      .filter_map(|entry| entry.ok())
+      .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+      .filter_map(|entry| {
+        let meta = entry.metadata().ok()?;
+        Some((entry.path(), meta.modified().ok()?, meta.len()))
+      })
+      .collect();
+    let expiry = SystemTime::now() - self.ttl;
+    files.retain(|(path, modified, _)| {
+      if *modified >= expiry {
+        return true;
+      }
+      let _ = fs::remove_file(path);
+      false
+    });
+    files.sort_by_key(|(_, modified, _)| *modified);
+    let mut total: u64 = files.iter().map(|(_, _, len)| len).sum();
+    for (path, _, len) in files {
+      if total <= self.max_bytes {
+        break;
+      }
+      let _ = fs::remove_file(path);
+      total -= len;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn open(ttl_secs: u64, max_bytes: u64) -> DiskCache {
+    let path = std::env::temp_dir().join(format!("copilot-rs-test-{}", Uuid::new_v4()));
+    DiskCache::open(&DiskCacheOptions {
+      enabled: true,
+      path: Some(path.to_string_lossy().to_string()),
+      ttl_secs,
+      max_bytes,
+    }).unwrap()
+  }
+
+  #[test]
+  fn round_trips_by_prompt_and_settings() {
+    let cache = open(60, u64::MAX);
+    let params = DocParams::from_text("let x", "");
+    let settings = CompletionSettings::default();
+    let response = CopilotCompletionResponse { completions: vec![], cancellation_reason: None };
+    cache.set(&prompt_key(&params, &settings), &response);
+
+    assert!(cache.get(&prompt_key(&params, &settings)).is_some());
+    let other_model = CompletionSettings { model: "other-model".to_string(), ..CompletionSettings::default() };
+    assert!(cache.get(&prompt_key(&params, &other_model)).is_none());
+    assert!(cache.get(&prompt_key(&DocParams::from_text("let y", ""), &settings)).is_none());
+    fs::remove_dir_all(&cache.dir).unwrap();
+  }
+
+  #[test]
+  fn prunes_to_size_cap() {
+    let cache = open(60, 0);
+    let response = CopilotCompletionResponse { completions: vec![], cancellation_reason: None };
+    cache.set("a", &response);
+    cache.prune();
+    assert!(cache.get("a").is_none());
+    fs::remove_dir_all(&cache.dir).unwrap();
+  }
+}
@@ -0,0 +1,168 @@
+use std::{borrow::Cow, fmt, io, time::Duration};
+use github_device_flow::DeviceFlowError;
+use reqwest::StatusCode;
+use serde_json::json;
+use tower_lsp::jsonrpc::{self, ErrorCode};
+use tower_lsp::lsp_types::Position;
+
+// server defined json-rpc codes, the range -32000 to -32099 is reserved for them
+pub const NOT_SIGNED_IN: i64 = -32001;
+pub const AUTH_FAILED: i64 = -32002;
+pub const TRANSPORT_FAILED: i64 = -32003;
+pub const UPSTREAM_FAILED: i64 = -32004;
+pub const PROTOCOL_FAILED: i64 = -32005;
+pub const RATE_LIMITED: i64 = -32006;
+
+/// Everything that can go wrong between the editor and the completion servers.
+#[derive(Debug)]
+pub enum CopilotError {
+  /// No github account is signed in.
+  NotSignedIn,
+  /// The device flow, the hosts file or the copilot token grant failed.
+  Auth(String),
+  /// Settings that don't deserialize.
+  Config(String),
+  /// The server could not be reached.
+  Transport(reqwest::Error),
+  /// The server answered with a non-success status.
+  Upstream(StatusCode),
+  /// The server asked to slow down for longer than we were willing to wait, if it said how long.
+  RateLimited(Option<Duration>),
+  /// The server's answer could not be understood.
+  Protocol(String),
+  /// A request named a document that was never opened.
+  UnknownDocument(String),
+  /// A position outside of the document.
+  InvalidPosition(Position),
+  Io(io::Error),
+}
+
+pub type CopilotResult<T> = Result<T, CopilotError>;
+
+impl CopilotError {
+  fn kind(&self) -> &'static str {
+    match self {
+      Self::NotSignedIn => "notSignedIn",
+      Self::Auth(_) => "auth",
+      Self::Config(_) => "config",
+      Self::Transport(_) => "transport",
+      Self::Upstream(_) => "upstream",
+      Self::RateLimited(_) => "rateLimited",
+      Self::Protocol(_) => "protocol",
+      Self::UnknownDocument(_) => "unknownDocument",
+      Self::InvalidPosition(_) => "invalidPosition",
+      Self::Io(_) => "io",
+    }
+  }
+
+  pub fn code(&self) -> ErrorCode {
+    match self {
+      Self::NotSignedIn => ErrorCode::ServerError(NOT_SIGNED_IN),
+      Self::Auth(_) => ErrorCode::ServerError(AUTH_FAILED),
+      Self::Transport(_) => ErrorCode::ServerError(TRANSPORT_FAILED),
+      Self::Upstream(_) => ErrorCode::ServerError(UPSTREAM_FAILED),
+      Self::RateLimited(_) => ErrorCode::ServerError(RATE_LIMITED),
+      Self::Protocol(_) => ErrorCode::ServerError(PROTOCOL_FAILED),
+      Self::Config(_) | Self::UnknownDocument(_) | Self::InvalidPosition(_) => ErrorCode::InvalidParams,
+      Self::Io(_) => ErrorCode::InternalError,
+    }
+  }
+
+  /// The `cancellationReason` a completion request failing this way is answered with instead of an error.
+  pub fn cancellation_reason(&self) -> Option<&'static str> {
+    match self {
+      Self::RateLimited(_) => Some("RateLimited"),
+      Self::Upstream(status) if status.is_server_error() => Some("ServerError"),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for CopilotError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::NotSignedIn => write!(f, "NotSignedIn"),
+      Self::Auth(e) => write!(f, "authentication failed: {}", e),
+      Self::Config(e) => write!(f, "invalid configuration: {}", e),
+      Self::Transport(e) => write!(f, "request failed: {}", e),
+      Self::Upstream(status) => write!(f, "server responded with {}", status),
+      Self::RateLimited(Some(wait)) => write!(f, "rate limited, retry in {}s", wait.as_secs()),
+      Self::RateLimited(None) => write!(f, "rate limited"),
+      Self::Protocol(e) => write!(f, "unexpected response: {}", e),
+      Self::UnknownDocument(uri) => write!(f, "unknown document {}", uri),
+      Self::InvalidPosition(pos) => write!(f, "position {}:{} is outside of the document", pos.line, pos.character),
+      Self::Io(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for CopilotError {}
+
+impl From<reqwest::Error> for CopilotError {
+  fn from(e: reqwest::Error) -> Self {
+    match e.status() {
+      // rejected credentials, whether for the token grant or a completion request
+      Some(status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)) => Self::Auth(format!("server responded with {}", status)),
+      Some(status) => Self::Upstream(status),
+      None if e.is_decode() => Self::Protocol(e.to_string()),
+      None => Self::Transport(e),
+    }
+  }
+}
+
+impl From<serde_json::Error> for CopilotError {
+  fn from(e: serde_json::Error) -> Self {
+    Self::Protocol(e.to_string())
+  }
+}
+
+impl From<DeviceFlowError> for CopilotError {
+  fn from(e: DeviceFlowError) -> Self {
+    Self::Auth(e.to_string())
+  }
+}
+
+impl From<io::Error> for CopilotError {
+  fn from(e: io::Error) -> Self {
+    Self::Io(e)
+  }
+}
+
+// blocking work only fails to join when it panicked
+impl From<tokio::task::JoinError> for CopilotError {
+  fn from(e: tokio::task::JoinError) -> Self {
+    Self::Io(io::Error::new(io::ErrorKind::Other, e))
+  }
+}
+
+impl From<CopilotError> for jsonrpc::Error {
+  fn from(e: CopilotError) -> Self {
+    jsonrpc::Error {
+      code: e.code(),
+      message: Cow::from(e.to_string()),
+      data: Some(json!({ "kind": e.kind() })),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn maps_to_json_rpc_errors() {
+    let e = jsonrpc::Error::from(CopilotError::UnknownDocument("file:///a.rs".to_string()));
+    assert_eq!(e.code.code(), -32602);
+    assert_eq!(e.data, Some(json!({ "kind": "unknownDocument" })));
+    assert_eq!(jsonrpc::Error::from(CopilotError::NotSignedIn).code.code(), NOT_SIGNED_IN);
+    assert_eq!(jsonrpc::Error::from(CopilotError::Upstream(StatusCode::BAD_GATEWAY)).code.code(), UPSTREAM_FAILED);
+  }
+
+  #[test]
+  fn names_cancellation_reasons() {
+    assert_eq!(CopilotError::RateLimited(None).cancellation_reason(), Some("RateLimited"));
+    assert_eq!(CopilotError::Upstream(StatusCode::BAD_GATEWAY).cancellation_reason(), Some("ServerError"));
+    assert_eq!(CopilotError::Upstream(StatusCode::FORBIDDEN).cancellation_reason(), None);
+    assert_eq!(CopilotError::NotSignedIn.cancellation_reason(), None);
+  }
+}
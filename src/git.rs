@@ -0,0 +1,184 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::RwLock};
+use tower_lsp::lsp_types::Url;
+
+/// The directory holding the repository's config, `.git` may also be a file pointing elsewhere
+/// for worktrees and submodules.
+fn git_dir(root: &Path) -> Option<PathBuf> {
+  let dot_git = root.join(".git");
+  if dot_git.is_dir() {
+    return Some(dot_git);
+  }
+  let contents = fs::read_to_string(&dot_git).ok()?;
+  let target = contents.trim().strip_prefix("gitdir:")?.trim();
+  Some(root.join(target))
+}
+
+/// The closest ancestor of `path` containing a `.git` entry.
+pub fn find_repo_root(path: &Path) -> Option<PathBuf> {
+  path.ancestors()
+    .find(|dir| dir.join(".git").exists())
+    .map(Path::to_path_buf)
+}
+
+/// Remote names and urls in the order they appear in a git config file.
+pub fn parse_remotes(config: &str) -> Vec<(String, String)> {
+  let mut remotes = vec![];
+  let mut remote = None;
+  for line in config.lines().map(str::trim) {
+    if line.starts_with('[') {
+      remote = line.strip_prefix("[remote \"")
+        .and_then(|rest| rest.strip_suffix("\"]"))
+        .map(str::to_string);
+      continue;
+    }
+    let Some(name) = &remote else { continue };
+    let Some((key, value)) = line.split_once('=') else { continue };
+    if key.trim() == "url" {
+      remotes.push((name.to_string(), value.trim().trim_matches('"').to_string()));
+    }
+  }
+  remotes
+}
+
+// worktrees keep their config in the main repository's git dir
+fn read_config(git_dir: &Path) -> Option<String> {
+  fs::read_to_string(git_dir.join("config")).ok().or_else(|| {
+    let common = fs::read_to_string(git_dir.join("commondir")).ok()?;
+    fs::read_to_string(git_dir.join(common.trim()).join("config")).ok()
+  })
+}
+
+// github.com itself and its subdomains, e.g. ssh.github.com for ssh over port 443,
+// and the configured GitHub Enterprise hosts
+fn is_github_host(authority: &str, enterprise_hosts: &[String]) -> bool {
+  let host = authority.rsplit('@').next().unwrap_or(authority);
+  let host = host.split(':').next().unwrap_or(host).to_ascii_lowercase();
+  host == "github.com" || host.ends_with(".github.com")
+    || enterprise_hosts.iter().any(|enterprise| enterprise.eq_ignore_ascii_case(&host))
+}
+
+/// `owner/name` from a GitHub or GitHub Enterprise remote in ssh, scp-like or https form,
+/// remotes on any other host have none.
+pub fn parse_nwo(url: &str, enterprise_hosts: &[String]) -> Option<String> {
+  let (authority, path) = match url.split_once("://") {
+    // https://github.com/owner/name, ssh://git@ghe.example.com:2222/owner/name.git
+    Some((_, rest)) => rest.split_once('/')?,
+    // git@github.com:owner/name.git
+    None => url.split_once(':')?,
+  };
+  if !is_github_host(authority, enterprise_hosts) {
+    return None;
+  }
+  let mut segments = path.trim_end_matches('/').trim_end_matches(".git").rsplit('/');
+  let name = segments.next().filter(|name| !name.is_empty())?;
+  let owner = segments.next().filter(|owner| !owner.is_empty())?;
+  Some(format!("{}/{}", owner, name))
+}
+
+// origin when it is on a GitHub host, otherwise the first remote that is
+fn repo_nwo(root: &Path, enterprise_hosts: &[String]) -> Option<String> {
+  let remotes = parse_remotes(&read_config(&git_dir(root)?)?);
+  let (origin, others): (Vec<_>, Vec<_>) = remotes.iter().partition(|(name, _)| name == "origin");
+  origin.into_iter().chain(others).find_map(|(_, url)| parse_nwo(url, enterprise_hosts))
+}
+
+/// Remembers the nwo of every repository documents were opened from, and the repository
+/// of every directory so its ancestors are only searched once.
+#[derive(Debug, Default)]
+pub struct Repositories {
+  roots: RwLock<HashMap<PathBuf, Option<PathBuf>>>,
+  nwo: RwLock<HashMap<PathBuf, Option<String>>>,
+  enterprise_hosts: RwLock<Vec<String>>,
+}
+
+impl Repositories {
+  fn repo_root(&self, dir: &Path) -> Option<PathBuf> {
+    if let Some(root) = self.roots.read().unwrap().get(dir) {
+      return root.clone();
+    }
+    let root = find_repo_root(dir);
+    self.roots.write().unwrap().insert(dir.to_path_buf(), root.clone());
+    root
+  }
+
+  pub fn nwo_for(&self, uri: &str) -> Option<String> {
+    let path = Url::parse(uri).ok()?.to_file_path().ok()?;
+    let root = self.repo_root(path.parent()?)?;
+    if let Some(nwo) = self.nwo.read().unwrap().get(&root) {
+      return nwo.clone();
+    }
+    let nwo = repo_nwo(&root, &self.enterprise_hosts.read().unwrap());
+    self.nwo.write().unwrap().insert(root, nwo.clone());
+    nwo
+  }
+
+  /// GitHub Enterprise hosts whose remotes count as well, remotes are read again when they change.
+  pub fn set_enterprise_hosts(&self, hosts: Vec<String>) {
+    let mut current = self.enterprise_hosts.write().unwrap();
+    if *current != hosts {
+      *current = hosts;
+      self.nwo.write().unwrap().clear();
+    }
+  }
+
+  /// Forgets everything, remotes are read again on the next request.
+  pub fn clear(&self) {
+    self.roots.write().unwrap().clear();
+    self.nwo.write().unwrap().clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_remote_urls() {
+    let enterprise = vec!["ghe.example.com".to_string()];
+    for url in [
+      "git@github.com:zbirenbaum/copilot-rs.git",
+      "ssh://git@github.com/zbirenbaum/copilot-rs.git",
+      "ssh://git@ssh.github.com:443/zbirenbaum/copilot-rs",
+      "ssh://git@ghe.example.com:2222/zbirenbaum/copilot-rs",
+      "https://github.com/zbirenbaum/copilot-rs",
+      "https://user@GitHub.com/zbirenbaum/copilot-rs.git/",
+      "https://user@ghe.example.com/zbirenbaum/copilot-rs.git/",
+    ] {
+      assert_eq!(parse_nwo(url, &enterprise).as_deref(), Some("zbirenbaum/copilot-rs"), "{}", url);
+    }
+    assert_eq!(parse_nwo("https://github.com/", &enterprise), None);
+    assert_eq!(parse_nwo("/srv/git/project", &enterprise), None);
+    assert_eq!(parse_nwo("git@gitlab.com:zbirenbaum/copilot-rs.git", &enterprise), None);
+    assert_eq!(parse_nwo("https://github.com.evil.example/zbirenbaum/copilot-rs", &enterprise), None);
+    // enterprise hosts only count once configured
+    assert_eq!(parse_nwo("git@ghe.example.com:zbirenbaum/copilot-rs.git", &[]), None);
+  }
+
+  #[test]
+  fn reads_remotes_from_config() {
+    let config = "[core]\n\tbare = false\n[remote \"upstream\"]\n\turl = git@github.com:a/b.git\n\tfetch = +refs/heads/*:refs/remotes/upstream/*\n[remote \"origin\"]\n\turl = https://github.com/c/d\n[branch \"main\"]\n\tremote = origin\n";
+    assert_eq!(parse_remotes(config), vec![
+      ("upstream".to_string(), "git@github.com:a/b.git".to_string()),
+      ("origin".to_string(), "https://github.com/c/d".to_string()),
+    ]);
+
+    let dir = std::env::temp_dir().join(format!("copilot-rs-git-{}", std::process::id()));
+    fs::create_dir_all(dir.join(".git")).unwrap();
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join(".git/config"), config).unwrap();
+    fs::create_dir_all(dir.join("src/bin")).unwrap();
+    let repositories = Repositories::default();
+    for file in ["src/main.rs", "src/bin/tool.rs"] {
+      let uri = Url::from_file_path(dir.join(file)).unwrap();
+      assert_eq!(repositories.nwo_for(uri.as_str()).as_deref(), Some("c/d"));
+    }
+    // both directories share the repository's entry
+    assert_eq!(repositories.nwo.read().unwrap().len(), 1);
+
+    fs::write(dir.join(".git/config"), "[remote \"origin\"]\n\turl = git@ghe.example.com:e/f.git\n").unwrap();
+    repositories.set_enterprise_hosts(vec!["ghe.example.com".to_string()]);
+    let uri = Url::from_file_path(dir.join("src/main.rs")).unwrap();
+    assert_eq!(repositories.nwo_for(uri.as_str()).as_deref(), Some("e/f"));
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
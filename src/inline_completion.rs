@@ -0,0 +1,203 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::task::{Context, Poll};
+use futures::future::BoxFuture;
+use tower::Service;
+use tower_lsp::jsonrpc::{Id, Request, Response};
+use tower_lsp::lsp_types::*;
+use crate::copilot::CopilotCyclingCompletion;
+use crate::parse::PositionEncoding;
+
+// lsp-types 0.94 predates LSP 3.18, so the inline completion types are defined here
+
+pub const ACCEPT_COMMAND: &str = "copilot-rs.acceptCompletion";
+
+/// How an inline completion was triggered.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct InlineCompletionTriggerKind(i32);
+
+impl InlineCompletionTriggerKind {
+  /// Explicitly requested by the user.
+  pub const INVOKED: InlineCompletionTriggerKind = InlineCompletionTriggerKind(1);
+  /// Requested automatically while typing.
+  pub const AUTOMATIC: InlineCompletionTriggerKind = InlineCompletionTriggerKind(2);
+}
+
+/// The completion item currently selected in the editor's completion widget.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedCompletionInfo {
+  pub range: Range,
+  pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionContext {
+  pub trigger_kind: InlineCompletionTriggerKind,
+  pub selected_completion_info: Option<SelectedCompletionInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionParams {
+  #[serde(flatten)]
+  pub text_document_position: TextDocumentPositionParams,
+  pub context: InlineCompletionContext,
+  #[serde(flatten)]
+  pub work_done_progress_params: WorkDoneProgressParams,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionItem {
+  pub insert_text: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub filter_text: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub range: Option<Range>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionList {
+  pub items: Vec<InlineCompletionItem>,
+}
+
+impl InlineCompletionParams {
+  pub fn to_completion_params(&self) -> CompletionParams {
+    CompletionParams {
+      text_document_position: self.text_document_position.clone(),
+      work_done_progress_params: self.work_done_progress_params.clone(),
+      partial_result_params: PartialResultParams::default(),
+      context: None,
+    }
+  }
+}
+
+impl InlineCompletionItem {
+  /// Replaces the line up to the cursor, the same way the cycling completions do.
+  pub fn from_cycling(completion: &CopilotCyclingCompletion) -> Self {
+    Self {
+      insert_text: completion.text.to_string(),
+      filter_text: Some(completion.text.to_string()),
+      range: Some(Range {
+        start: Position { line: completion.position.line, character: 0 },
+        end: completion.position
+      }),
+      command: Some(Command {
+        title: "Accept Copilot suggestion".to_string(),
+        command: ACCEPT_COMMAND.to_string(),
+        arguments: Some(vec![json!(completion.text)]),
+      }),
+    }
+  }
+}
+
+/// Whether a completion agrees with the item selected in the completion widget.
+/// The line text up to the selection range, followed by the selected text, must be a prefix of it.
+pub fn matches_selected(
+  completion: &CopilotCyclingCompletion,
+  line_before: &str,
+  selected: &SelectedCompletionInfo,
+  encoding: PositionEncoding
+) -> bool {
+  if selected.range.start.line != completion.position.line {
+    return true;
+  }
+  let kept = &line_before[..encoding.byte_index(line_before, selected.range.start.character as usize)];
+  completion.text.starts_with(&format!("{}{}", kept, selected.text))
+}
+
+/// The initialize result type has no field for `inlineCompletionProvider`,
+/// so it is added to the serialized response on the way out.
+/// Only the response answering `initialize_id` is touched.
+pub fn advertise_capability(response: Option<Response>, initialize_id: &Id) -> Option<Response> {
+  let (id, body) = response?.into_parts();
+  if &id != initialize_id {
+    return Some(Response::from_parts(id, body));
+  }
+  let body = body.map(|mut result: Value| {
+    if let Some(capabilities) = result.get_mut("capabilities").and_then(Value::as_object_mut) {
+      capabilities.insert("inlineCompletionProvider".to_string(), json!({}));
+    }
+    result
+  });
+  Some(Response::from_parts(id, body))
+}
+
+/// Wraps the language server to advertise inline completions in its `initialize` response.
+#[derive(Debug, Clone)]
+pub struct AdvertiseCapability<S> {
+  inner: S,
+}
+
+impl<S> AdvertiseCapability<S> {
+  pub fn new(inner: S) -> Self {
+    Self { inner }
+  }
+}
+
+impl<S> Service<Request> for AdvertiseCapability<S>
+where
+  S: Service<Request, Response = Option<Response>>,
+  S::Future: Send + 'static,
+{
+  type Response = Option<Response>;
+  type Error = S::Error;
+  type Future = BoxFuture<'static, Result<Option<Response>, S::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: Request) -> Self::Future {
+    let initialize_id = request.id().filter(|_| request.method() == "initialize").cloned();
+    let response = self.inner.call(request);
+    Box::pin(async move {
+      let response = response.await?;
+      Ok(match initialize_id {
+        Some(id) => advertise_capability(response, &id),
+        None => response,
+      })
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn selected(start: u32, text: &str) -> SelectedCompletionInfo {
+    let range = Range { start: Position { line: 0, character: start }, end: Position { line: 0, character: 6 } };
+    SelectedCompletionInfo { range, text: text.to_string() }
+  }
+
+  #[test]
+  fn matches_the_selected_item() {
+    let position = Position { line: 0, character: 6 };
+    let completion = CopilotCyclingCompletion::new("_bytes(buf)".to_string(), "  read".to_string(), position, PositionEncoding::Utf16);
+    assert!(matches_selected(&completion, "  read", &selected(2, "read_bytes"), PositionEncoding::Utf16));
+    assert!(!matches_selected(&completion, "  read", &selected(2, "read_line"), PositionEncoding::Utf16));
+
+    // the selection counts utf-16 units, "é" is one of them but two bytes
+    let completion = CopilotCyclingCompletion::new("ment".to_string(), "é.ele".to_string(), position, PositionEncoding::Utf16);
+    assert!(matches_selected(&completion, "é.ele", &selected(2, "element"), PositionEncoding::Utf16));
+  }
+
+  #[test]
+  fn patches_only_the_initialize_response() {
+    let result = json!({ "capabilities": { "positionEncoding": "utf-16" } });
+    let initialize = Response::from_ok(Id::Number(1), result.clone());
+    let patched = advertise_capability(Some(initialize), &Id::Number(1)).unwrap();
+    assert_eq!(patched.result().unwrap()["capabilities"]["inlineCompletionProvider"], json!({}));
+
+    let other = Response::from_ok(Id::Number(2), result.clone());
+    let untouched = advertise_capability(Some(other), &Id::Number(1)).unwrap();
+    assert_eq!(untouched.result(), Some(&result));
+    assert!(advertise_capability(None, &Id::Number(1)).is_none());
+  }
+}
@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+
+/// How a language writes a comment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comment {
+  Line(&'static str),
+  Block(&'static str, &'static str),
+}
+
+/// Language specific knowledge, looked up by the lsp `languageId`.
+#[derive(Debug)]
+pub struct Language {
+  pub ids: &'static [&'static str],
+  /// The syntax comments are written in.
+  pub comment: Comment,
+  /// Another block comment syntax recognized next to a line comment one.
+  pub block_comment: Option<(&'static str, &'static str)>,
+  /// Delimiters of string literals, longer ones first since they may start with a shorter one.
+  /// Literals end with their line unless the delimiter is `` ` `` or longer than one character.
+  pub quotes: &'static [&'static str],
+  /// Stop sequences ending a multi-line completion, usually the start of the next top level item.
+  pub block_stops: &'static [&'static str],
+  /// Single quoted character literals like `'{'`, in languages where a lone `'` means something else.
+  pub char_literals: bool,
+}
+
+const C_BLOCK_COMMENT: Option<(&str, &str)> = Some(("/*", "*/"));
+
+const LANGUAGES: &[Language] = &[
+  Language {
+    ids: &[
+      "c", "cpp", "csharp", "cuda-cpp", "dart", "go", "groovy", "java", "javascript", "javascriptreact",
+      "jsonc", "kotlin", "less", "objective-c", "objective-cpp", "php", "proto", "scala", "scss",
+      "solidity", "swift", "typescript", "typescriptreact", "zig",
+    ],
+    comment: Comment::Line("//"),
+    block_comment: C_BLOCK_COMMENT,
+    quotes: &["\"", "'", "`"],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language {
+    // single quotes also start lifetimes, so they are only recognized around character literals
+    ids: &["rust"],
+    comment: Comment::Line("//"),
+    block_comment: C_BLOCK_COMMENT,
+    quotes: &["\""],
+    block_stops: &[],
+    char_literals: true,
+  },
+  Language {
+    // single quotes start generic type parameters like 'T
+    ids: &["fsharp"],
+    comment: Comment::Line("//"),
+    block_comment: Some(("(*", "*)")),
+    quotes: &["\""],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language {
+    ids: &["python", "gdscript"],
+    comment: Comment::Line("#"),
+    block_comment: None,
+    quotes: &["\"\"\"", "'''", "\"", "'"],
+    block_stops: &["\ndef ", "\nclass ", "\nif __name__"],
+    char_literals: false,
+  },
+  Language {
+    ids: &[
+      "ruby", "perl", "shellscript", "sh", "bash", "zsh", "fish", "r", "yaml", "toml",
+      "dockerfile", "makefile", "cmake", "powershell", "julia", "elixir", "coffeescript", "nim", "nix",
+      "terraform", "graphql",
+    ],
+    comment: Comment::Line("#"),
+    block_comment: None,
+    quotes: &["\"", "'"],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language {
+    ids: &["lua"],
+    comment: Comment::Line("--"),
+    block_comment: Some(("--[[", "]]")),
+    quotes: &["\"", "'"],
+    block_stops: &["\nfunction ", "\nlocal function "],
+    char_literals: false,
+  },
+  Language {
+    ids: &["sql"],
+    comment: Comment::Line("--"),
+    block_comment: C_BLOCK_COMMENT,
+    quotes: &["\"", "'"],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language {
+    ids: &["haskell", "elm"],
+    comment: Comment::Line("--"),
+    block_comment: Some(("{-", "-}")),
+    quotes: &["\""],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language { ids: &["ada"], comment: Comment::Line("--"), block_comment: None, quotes: &["\""], block_stops: &[], char_literals: false },
+  Language {
+    ids: &["clojure", "lisp", "scheme", "racket", "ini"],
+    comment: Comment::Line(";"),
+    block_comment: None,
+    quotes: &["\""],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language {
+    ids: &["erlang", "latex", "tex", "bibtex", "matlab"],
+    comment: Comment::Line("%"),
+    block_comment: None,
+    quotes: &["\""],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language { ids: &["vim"], comment: Comment::Line("\""), block_comment: None, quotes: &["'"], block_stops: &[], char_literals: false },
+  Language { ids: &["fortran"], comment: Comment::Line("!"), block_comment: None, quotes: &["\"", "'"], block_stops: &[], char_literals: false },
+  Language {
+    ids: &["html", "xml", "xsl", "markdown", "vue", "svelte"],
+    comment: Comment::Block("<!--", "-->"),
+    block_comment: None,
+    quotes: &["\""],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language {
+    ids: &["css"],
+    comment: Comment::Block("/*", "*/"),
+    block_comment: None,
+    quotes: &["\"", "'"],
+    block_stops: &[],
+    char_literals: false,
+  },
+  Language { ids: &["ocaml"], comment: Comment::Block("(*", "*)"), block_comment: None, quotes: &["\""], block_stops: &[], char_literals: false },
+];
+
+impl Language {
+  pub fn find(language_id: &str) -> Option<&'static Language> {
+    LANGUAGES.iter().find(|language| language.ids.contains(&language_id))
+  }
+
+  pub fn line_comment(&self) -> Option<&'static str> {
+    match self.comment {
+      Comment::Line(start) => Some(start),
+      Comment::Block(..) => None,
+    }
+  }
+
+  pub fn block_comment(&self) -> Option<(&'static str, &'static str)> {
+    match self.comment {
+      Comment::Line(_) => self.block_comment,
+      Comment::Block(start, end) => Some((start, end)),
+    }
+  }
+
+  pub fn comment(&self, text: &str) -> String {
+    match self.comment {
+      Comment::Line(start) => format!("{} {}", start, text),
+      Comment::Block(start, end) => format!("{} {} {}", start, text, end),
+    }
+  }
+}
+
+/// The `Path:` comment put above the prompt, languages without a known comment syntax get none.
+pub fn path_header(language_id: &str, path: &str) -> Option<String> {
+  let language = Language::find(language_id)?;
+  Some(format!("{}\n", language.comment(&format!("Path: {}", path))))
+}
+
+/// `path` relative to the innermost workspace folder containing it, unchanged otherwise.
+pub fn relative_path(path: &Path, workspace_folders: &[PathBuf]) -> String {
+  workspace_folders.iter()
+    .filter_map(|folder| path.strip_prefix(folder).ok())
+    .min_by_key(|relative| relative.components().count())
+    .unwrap_or(path)
+    .to_string_lossy()
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn comments_the_path_per_language() {
+    assert_eq!(path_header("rust", "src/main.rs").as_deref(), Some("// Path: src/main.rs\n"));
+    assert_eq!(path_header("python", "app.py").as_deref(), Some("# Path: app.py\n"));
+    assert_eq!(path_header("html", "index.html").as_deref(), Some("<!-- Path: index.html -->\n"));
+    assert_eq!(path_header("plaintext", "notes.txt"), None);
+  }
+
+  #[test]
+  fn relativizes_to_the_innermost_folder() {
+    let folders = vec![PathBuf::from("/work"), PathBuf::from("/work/nested")];
+    assert_eq!(relative_path(Path::new("/work/nested/src/lib.rs"), &folders), "src/lib.rs");
+    assert_eq!(relative_path(Path::new("/work/README.md"), &folders), "README.md");
+    assert_eq!(relative_path(Path::new("/tmp/scratch.rs"), &folders), "/tmp/scratch.rs");
+  }
+}
@@ -5,6 +5,17 @@ pub mod copilot;
 pub mod auth;
 pub mod parse;
 pub mod request;
+pub mod provider;
 pub mod backend;
 pub mod debounce;
 pub mod cache;
+pub mod config;
+pub mod disk_cache;
+pub mod inline_completion;
+pub mod tokenizer;
+pub mod git;
+pub mod language;
+pub mod snippets;
+pub mod postprocess;
+pub mod error;
+pub mod retry;
@@ -1,6 +1,7 @@
-use std::{sync::{Arc, RwLock}, collections::HashMap};
-use copilot_rs::{backend::Backend, auth, copilot::CopilotEditorInfo, cache::CopilotCache};
+use std::{sync::{Arc, Mutex, RwLock}, collections::HashMap};
+use copilot_rs::{backend::Backend, auth, inline_completion, parse::PositionEncoding, config::Config, provider::Providers, copilot::CopilotEditorInfo, cache::CopilotCache, git::Repositories};
 use tower_lsp::{LspService, Server};
+use tower::ServiceBuilder;
 use reqwest::header::{HeaderMap, HeaderValue};
 
 #[tokio::main]
@@ -15,14 +16,9 @@ async fn main() {
   let (stdin, stdout) = (stdin.compat(), stdout.compat_write());
   // let copilot_handler = CopilotHandler::new();
 
-  let auth_grant = auth::get_copilot_token().await.unwrap();
-  let auth_token = auth_grant.token.to_string();
-  let auth_string = format!("Bearer {}", &auth_token.as_str());
-  let mut auth_value = HeaderValue::from_str(&auth_string).unwrap();
-  auth_value.set_sensitive(true);
+  let token = auth::TokenManager::new().await;
   let machine_id = auth::get_machine_id();
   let mut header_map = HeaderMap::new();
-  header_map.insert("Authorization", auth_value);
   header_map.insert("Openai-Organization", HeaderValue::from_static("github-copilot"));
   header_map.insert("VScode-MachineId", HeaderValue::from_str(&machine_id).unwrap());
   header_map.insert("Editor-Version", HeaderValue::from_static("JetBrains-IC/231.9011.34"));
@@ -37,16 +33,30 @@ async fn main() {
       Backend {
         client: client.clone(),
         documents: Arc::new(RwLock::new(HashMap::new())),
-        http_client: Arc::new(http_client),
-        current_dispatch: None,
+        providers: Providers::new(Arc::new(http_client), Arc::clone(&token)),
+        token: Arc::clone(&token),
+        current_dispatch: Mutex::new(HashMap::new()),
         runner: copilot_rs::debounce::Runner::new(tokio::time::Duration::from_millis(100)),
         editor_info: Arc::new(RwLock::new(CopilotEditorInfo::default())),
+        position_encoding: Arc::new(RwLock::new(PositionEncoding::default())),
+        config: Arc::new(RwLock::new(Config::default())),
+        repositories: Repositories::default(),
+        workspace_folders: Arc::new(RwLock::new(vec![])),
         cache: CopilotCache::new()
       }
   ).custom_method("getCompletionsCycling", Backend::get_completions_cycling)
     .custom_method("setEditorInfo", Backend::set_editor_info)
+    .custom_method("signInInitiate", Backend::sign_in_initiate)
+    .custom_method("signInConfirm", Backend::sign_in_confirm)
+    .custom_method("signOut", Backend::sign_out)
+    .custom_method("checkStatus", Backend::check_status)
+    .custom_method("getCacheStats", Backend::get_cache_stats)
+    .custom_method("textDocument/inlineCompletion", Backend::inline_completion)
     .finish();
 
+  let service = ServiceBuilder::new()
+    .layer_fn(inline_completion::AdvertiseCapability::new)
+    .service(service);
   Server::new(stdin, stdout, socket)
     .serve(service)
     .await;
@@ -1,22 +1,128 @@
-use ropey::Rope;
-use tower_lsp::lsp_types::Position;
+use ropey::{Rope, RopeSlice};
+use tower_lsp::lsp_types::{Position, PositionEncodingKind, TextDocumentContentChangeEvent};
+use crate::error::{CopilotError, CopilotResult};
 
-pub fn offset_to_position(offset: usize, rope: &Rope) -> Option<Position> {
+/// Unit that `Position.character` is counted in, negotiated during initialize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+  Utf8,
+  #[default]
+  Utf16,
+  Utf32,
+}
+
+impl PositionEncoding {
+  /// Picks the client's most preferred encoding, falling back to the utf-16 default.
+  pub fn negotiate(offered: Option<&Vec<PositionEncodingKind>>) -> Self {
+    offered
+      .into_iter()
+      .flatten()
+      .find_map(|kind| match kind.as_str() {
+        "utf-8" => Some(Self::Utf8),
+        "utf-16" => Some(Self::Utf16),
+        "utf-32" => Some(Self::Utf32),
+        _ => None
+      })
+      .unwrap_or_default()
+  }
+
+  pub fn kind(&self) -> PositionEncodingKind {
+    match self {
+      Self::Utf8 => PositionEncodingKind::UTF8,
+      Self::Utf16 => PositionEncodingKind::UTF16,
+      Self::Utf32 => PositionEncodingKind::UTF32,
+    }
+  }
+
+  /// Length of `text` in this encoding's code units.
+  pub fn len(&self, text: &str) -> usize {
+    match self {
+      Self::Utf8 => text.len(),
+      Self::Utf16 => text.encode_utf16().count(),
+      Self::Utf32 => text.chars().count(),
+    }
+  }
+
+  /// Byte index into `text` after `units` code units, clamped to the end of the text.
+  pub fn byte_index(&self, text: &str, units: usize) -> usize {
+    let mut seen = 0;
+    for (idx, c) in text.char_indices() {
+      if seen >= units {
+        return idx;
+      }
+      seen += self.len(c.encode_utf8(&mut [0; 4]));
+    }
+    text.len()
+  }
+
+  fn units_to_chars(&self, line: RopeSlice, units: usize) -> usize {
+    match self {
+      Self::Utf8 => line.byte_to_char(units.min(line.len_bytes())),
+      Self::Utf16 => line.utf16_cu_to_char(units.min(line.len_utf16_cu())),
+      Self::Utf32 => units.min(line.len_chars()),
+    }
+  }
+
+  fn chars_to_units(&self, line: RopeSlice, chars: usize) -> usize {
+    match self {
+      Self::Utf8 => line.char_to_byte(chars),
+      Self::Utf16 => line.char_to_utf16_cu(chars),
+      Self::Utf32 => chars,
+    }
+  }
+}
+
+// the line without its terminator, positions past the end clamp to this
+fn line_content(rope: &Rope, line_idx: usize) -> Option<RopeSlice> {
+  let line = rope.get_line(line_idx)?;
+  let mut len = line.len_chars();
+  while len > 0 && matches!(line.char(len - 1), '\n' | '\r') {
+    len -= 1;
+  }
+  Some(line.slice(..len))
+}
+
+pub fn offset_to_position(offset: usize, rope: &Rope, encoding: PositionEncoding) -> Option<Position> {
   let line = rope.try_char_to_line(offset).ok()?;
   let first_char_of_line = rope.try_line_to_char(line).ok()?;
-  let column = offset - first_char_of_line;
+  let column = encoding.chars_to_units(rope.line(line), offset - first_char_of_line);
   Some(Position::new(line as u32, column as u32))
 }
 
-pub fn position_to_offset(position: Position, rope: &Rope) -> Option<usize> {
-  Some(rope.try_line_to_char(position.line as usize).ok()? + position.character as usize)
+/// Characters past the end of a line clamp to it, lines past the end of the document are an error.
+pub fn position_to_offset(position: Position, rope: &Rope, encoding: PositionEncoding) -> CopilotResult<usize> {
+  let line_idx = position.line as usize;
+  if line_idx == rope.len_lines() {
+    return Ok(rope.len_chars());
+  }
+  let content = line_content(rope, line_idx).ok_or(CopilotError::InvalidPosition(position))?;
+  let column = encoding.units_to_chars(content, position.character as usize);
+  Ok(rope.line_to_char(line_idx) + column)
+}
+
+/// Applies a single didChange event, changes without a range replace the whole document.
+/// A range that does not resolve means the editor's text and the rope disagree, it is rejected
+/// rather than applied somewhere else.
+pub fn apply_change(rope: &mut Rope, change: TextDocumentContentChangeEvent, encoding: PositionEncoding) -> CopilotResult<()> {
+  let Some(range) = change.range else {
+    *rope = Rope::from_str(&change.text);
+    return Ok(());
+  };
+  let start = position_to_offset(range.start, rope, encoding)?;
+  let end = position_to_offset(range.end, rope, encoding)?;
+  if end < start {
+    return Err(CopilotError::InvalidPosition(range.end));
+  }
+  rope.remove(start..end);
+  rope.insert(start, &change.text);
+  Ok(())
 }
 
 pub fn get_text_before(offset: usize, rope: &Rope) -> Option<String> {
   if offset == 0 {
     return Some("".to_string())
   }
-  Some(rope.slice(0..offset).to_string())
+  Some(rope.get_slice(0..offset)?.to_string())
 }
 
 pub fn get_text_after(offset: usize, rope: &Rope) -> Option<String> {
@@ -24,40 +130,106 @@ pub fn get_text_after(offset: usize, rope: &Rope) -> Option<String> {
   if offset == end_idx {
     return Some("".to_string());
   }
-  Some(rope.slice(offset..end_idx).to_string())
-}
-
-pub fn get_line_before(pos: Position, rope: &Rope) -> Option<String> {
-  let char_offset = pos.character as usize;
-  let offset = position_to_offset(pos, rope).unwrap();
-  if char_offset == 0 { return Some("".to_string()); }
-  let line_start = offset-char_offset;
-  Some(rope.slice(line_start..offset).to_string())
-}
-
-pub struct DocumentCompletionParams {
-  pub prefix: String,
-  pub prompt: String,
-  pub suffix: String,
-  pub line_before: String,
-}
-
-impl DocumentCompletionParams {
-  pub fn new(uri:String, position: Position, rope: Rope) -> Self {
-    let line_before = get_line_before(position, &rope).unwrap_or_default();
-    let offset = position_to_offset(position, &rope).unwrap_or_default();
-    let prefix = get_text_before(offset, &rope).unwrap_or_default();
-    let prompt = format!(
-      "// Path: {}\n{}",
-      uri,
-      prefix
-    );
-    let suffix = get_text_after(offset, &rope).unwrap();
-    Self {
-      prefix,
-      prompt,
-      suffix,
-      line_before
+  Some(rope.get_slice(offset..end_idx)?.to_string())
+}
+
+pub fn get_line_before(pos: Position, rope: &Rope, encoding: PositionEncoding) -> CopilotResult<String> {
+  let offset = position_to_offset(pos, rope, encoding)?;
+  let line_start = rope.try_line_to_char(pos.line as usize).map_or(offset, |start| start.min(offset));
+  Ok(rope.slice(line_start..offset).to_string())
+}
+
+/// Leading whitespace of a line, in characters.
+pub fn indentation(line: &str) -> usize {
+  line.chars().take_while(|c| *c == ' ' || *c == '\t').count()
+}
+
+/// Indentation of the first non-blank line after `line`, 0 at the end of the document.
+pub fn next_indent(rope: &Rope, line: usize) -> usize {
+  if line + 1 >= rope.len_lines() {
+    return 0;
+  }
+  rope.lines_at(line + 1)
+    .map(|line| line.to_string())
+    .find(|line| !line.trim().is_empty())
+    .map_or(0, |line| indentation(&line))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tower_lsp::lsp_types::Range;
+
+  fn change(start: (u32, u32), end: (u32, u32), text: &str) -> TextDocumentContentChangeEvent {
+    TextDocumentContentChangeEvent {
+      range: Some(Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))),
+      range_length: None,
+      text: text.to_string(),
+    }
+  }
+
+  #[test]
+  fn applies_incremental_changes() {
+    let mut rope = Rope::from_str("fn main() {\n}\n");
+    // later changes in the same event see the earlier ones applied
+    for c in [change((0, 3), (0, 7), "run"), change((0, 10), (0, 10), "\n  go();")] {
+      apply_change(&mut rope, c, PositionEncoding::Utf16).unwrap();
+    }
+    assert_eq!(rope.to_string(), "fn run() {\n  go();\n}\n");
+
+    apply_change(&mut rope, change((3, 0), (3, 0), "// end\n"), PositionEncoding::Utf16).unwrap();
+    assert_eq!(rope.to_string(), "fn run() {\n  go();\n}\n// end\n");
+
+    let mut rope = Rope::from_str("a\r\nb\r\n");
+    apply_change(&mut rope, change((0, 1), (1, 0), ""), PositionEncoding::Utf16).unwrap();
+    assert_eq!(rope.to_string(), "ab\r\n");
+
+    let full = TextDocumentContentChangeEvent { range: None, range_length: None, text: "new".to_string() };
+    apply_change(&mut rope, full, PositionEncoding::Utf16).unwrap();
+    assert_eq!(rope.to_string(), "new");
+  }
+
+  #[test]
+  fn round_trips_positions_per_encoding() {
+    let rope = Rope::from_str("a😀b\n中文 x\r\nlast");
+    for encoding in [PositionEncoding::Utf8, PositionEncoding::Utf16, PositionEncoding::Utf32] {
+      // between \r and \n is no position at all
+      for offset in (0..=rope.len_chars()).filter(|&offset| offset != 9) {
+        let position = offset_to_position(offset, &rope, encoding).unwrap();
+        assert_eq!(position_to_offset(position, &rope, encoding).unwrap(), offset, "{:?} at {}", encoding, offset);
+      }
     }
+    // "b" follows a four byte, two utf-16 unit emoji
+    assert_eq!(offset_to_position(2, &rope, PositionEncoding::Utf8), Some(Position::new(0, 5)));
+    assert_eq!(offset_to_position(2, &rope, PositionEncoding::Utf16), Some(Position::new(0, 3)));
+    assert_eq!(offset_to_position(2, &rope, PositionEncoding::Utf32), Some(Position::new(0, 2)));
+    assert_eq!(offset_to_position(6, &rope, PositionEncoding::Utf8), Some(Position::new(1, 6)));
+  }
+
+  #[test]
+  fn clamps_positions_inside_characters_and_lines() {
+    let rope = Rope::from_str("a😀b\r\nc");
+    // the middle of a surrogate pair or of a utf-8 sequence rounds down to the start of the character
+    assert_eq!(position_to_offset(Position::new(0, 2), &rope, PositionEncoding::Utf16).unwrap(), 1);
+    assert_eq!(position_to_offset(Position::new(0, 3), &rope, PositionEncoding::Utf8).unwrap(), 1);
+    // past the end of a line stops before its terminator, past the last line is an error
+    assert_eq!(position_to_offset(Position::new(0, 99), &rope, PositionEncoding::Utf16).unwrap(), 3);
+    assert_eq!(position_to_offset(Position::new(1, 99), &rope, PositionEncoding::Utf16).unwrap(), 6);
+    assert!(position_to_offset(Position::new(3, 0), &rope, PositionEncoding::Utf16).is_err());
+  }
+
+  #[test]
+  fn counts_only_lsp_line_breaks() {
+    let rope = Rope::from_str("a\u{2028}b\u{85}c\x0Cd\ne");
+    assert_eq!(rope.len_lines(), 2);
+    assert_eq!(position_to_offset(Position::new(1, 0), &rope, PositionEncoding::Utf16).unwrap(), 8);
+  }
+
+  #[test]
+  fn rejects_changes_outside_the_document() {
+    let mut rope = Rope::from_str("one\ntwo");
+    let result = apply_change(&mut rope, change((5, 0), (5, 0), "lost"), PositionEncoding::Utf16);
+    assert!(matches!(result, Err(CopilotError::InvalidPosition(_))));
+    assert_eq!(rope.to_string(), "one\ntwo");
   }
 }
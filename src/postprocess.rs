@@ -0,0 +1,142 @@
+use crate::language::Language;
+use crate::parse::indentation;
+
+// line endings after which the next lines form a new block
+const BLOCK_OPENERS: &[&str] = &["{", "(", "[", ":", "=>", "->", " do", " then", " else", "begin"];
+
+/// Whether a completion may span several lines, decided from the text around the cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionMode {
+  SingleLine,
+  MultiLine,
+}
+
+impl CompletionMode {
+  /// Multi-line right after a block opener, or on an empty line below one. Anywhere else,
+  /// including the middle of a line, only the current line is completed.
+  pub fn at_cursor(prefix: &str, suffix: &str) -> Self {
+    let rest_of_line = suffix.split('\n').next().unwrap_or_default();
+    // closing brackets inserted by the editor's auto pairing don't count as code after the cursor
+    if !rest_of_line.trim().chars().all(|c| matches!(c, ')' | ']' | '}')) {
+      return Self::SingleLine;
+    }
+    let mut lines = prefix.split('\n').rev();
+    let line_before = lines.next().unwrap_or_default();
+    let opener = match line_before.trim().is_empty() {
+      true => lines.find(|line| !line.trim().is_empty()).unwrap_or_default(),
+      false => line_before,
+    };
+    match BLOCK_OPENERS.iter().any(|block_opener| opener.trim_end().ends_with(block_opener)) {
+      true => Self::MultiLine,
+      false => Self::SingleLine,
+    }
+  }
+
+  /// Stop sequences for the request, followed by the ones configured by the user.
+  pub fn stop_sequences(&self, language_id: &str, configured: &[String]) -> Vec<String> {
+    let stops = match self {
+      Self::SingleLine => vec!["\n".to_string()],
+      Self::MultiLine => std::iter::once("\n\n\n")
+        .chain(Language::find(language_id).map_or(&[][..], |language| language.block_stops).iter().copied())
+        .map(str::to_string)
+        .collect(),
+    };
+    stops.into_iter().chain(configured.iter().cloned()).collect()
+  }
+}
+
+/// Cuts the completion at the earliest stop sequence, servers that ignore or limit `stop` are held to it here.
+pub fn truncate_at_stop(completion: &str, stops: &[String]) -> String {
+  let end = stops.iter()
+    .filter(|stop| !stop.is_empty())
+    .filter_map(|stop| completion.find(stop.as_str()))
+    .min()
+    .unwrap_or(completion.len());
+  completion[..end].to_string()
+}
+
+/// Cuts a multi-line completion before the first line indented less than the block it started in.
+/// The block's indentation is that of the cursor line, or of the first completed line when the
+/// cursor line is blank.
+pub fn trim_by_indentation(completion: &str, line_before: &str) -> String {
+  let mut block_indent = None;
+  let mut offset = 0;
+  for (i, line) in completion.split_inclusive('\n').enumerate() {
+    let full_line = if i == 0 { format!("{}{}", line_before, line) } else { line.to_string() };
+    if !full_line.trim().is_empty() {
+      let indent = indentation(&full_line);
+      match block_indent {
+        Some(block_indent) if indent < block_indent => return completion[..offset].trim_end().to_string(),
+        Some(_) => {},
+        None => block_indent = Some(indent),
+      }
+    }
+    offset += line.len();
+  }
+  completion.to_string()
+}
+
+// the end of a line of the suffix, so an overlap ending here repeats whole lines
+fn ends_line(suffix: &str, len: usize) -> bool {
+  let rest = &suffix[len..];
+  rest.is_empty() || rest.starts_with('\n') || rest.starts_with("\r\n")
+}
+
+/// Removes the longest tail of the completion that repeats whole lines the text after the cursor starts with.
+/// Overlaps within the cursor line, like a lone `)` or `;`, are kept since the completion may need them.
+pub fn trim_suffix_overlap(completion: &str, suffix: &str) -> String {
+  let overlap = (1..=completion.len().min(suffix.len())).rev()
+    .filter(|&len| suffix.is_char_boundary(len) && suffix[..len].contains('\n') && ends_line(suffix, len))
+    .find(|&len| completion.ends_with(&suffix[..len]))
+    .unwrap_or(0);
+  completion[..completion.len() - overlap].to_string()
+}
+
+/// Whether inserting the completion adds nothing but code that follows the cursor anyway.
+pub fn repeats_suffix(completion: &str, suffix: &str) -> bool {
+  let completion = completion.trim();
+  completion.is_empty() || suffix.trim_start().starts_with(completion)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cuts_at_the_first_dedent() {
+    let completion = "x > 0 {\n    return x;\n  }\n}\n\nfn next() {}";
+    assert_eq!(trim_by_indentation(completion, "  if "), "x > 0 {\n    return x;\n  }");
+    assert_eq!(trim_by_indentation("1;\n  let b = 2;", "  let a = "), "1;\n  let b = 2;");
+    // on a blank line the first completed line sets the block
+    assert_eq!(trim_by_indentation("\n    a();\n\n    b();\n}\n", ""), "\n    a();\n\n    b();");
+  }
+
+  #[test]
+  fn picks_the_completion_mode() {
+    assert_eq!(CompletionMode::at_cursor("fn main() {\n    ", "\n}"), CompletionMode::MultiLine);
+    assert_eq!(CompletionMode::at_cursor("fn main() {", "}"), CompletionMode::MultiLine);
+    assert_eq!(CompletionMode::at_cursor("def f(x):\n\n    ", ""), CompletionMode::MultiLine);
+    assert_eq!(CompletionMode::at_cursor("let x = foo(", "bar);"), CompletionMode::SingleLine);
+    assert_eq!(CompletionMode::at_cursor("let x = ", "\nlet y = 2;"), CompletionMode::SingleLine);
+
+    let stops = CompletionMode::MultiLine.stop_sequences("python", &["<END>".to_string()]);
+    assert_eq!(stops, vec!["\n\n\n", "\ndef ", "\nclass ", "\nif __name__", "<END>"]);
+    assert_eq!(CompletionMode::SingleLine.stop_sequences("rust", &[]), vec!["\n"]);
+    assert_eq!(truncate_at_stop("x = 1\n\ndef g():", &stops), "x = 1\n");
+    assert_eq!(truncate_at_stop("a + b\nc", &["\n".to_string()]), "a + b");
+  }
+
+  #[test]
+  fn removes_text_already_after_the_cursor() {
+    assert_eq!(trim_suffix_overlap("x = 1;\n  return x;", "\n  return x;\n}"), "x = 1;");
+    assert_eq!(trim_suffix_overlap("a, b", ")"), "a, b");
+    // a single closing character after the cursor may well be needed by the completion too
+    assert_eq!(trim_suffix_overlap("f(g(x))", ")\n}"), "f(g(x))");
+    assert_eq!(trim_suffix_overlap("bar();", ";"), "bar();");
+    // overlaps have to end where a line of the suffix does
+    assert_eq!(trim_suffix_overlap("x\n  retu", "\n  return x;"), "x\n  retu");
+    assert!(repeats_suffix("", "}"));
+    assert!(repeats_suffix("\n  return x;", "\n  return x;\n}"));
+    assert!(!repeats_suffix("x = 1;", "\n  return x;"));
+  }
+}
@@ -0,0 +1,324 @@
+use std::{fmt, sync::Arc, time::Duration};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use reqwest::{Client, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use crate::auth::TokenManager;
+use crate::config::{CompletionSettings, ProviderKind};
+use crate::copilot::{choice_stream, find_stop, Completion, DocParams};
+use crate::error::{CopilotError, CopilotResult};
+use crate::request::{build_request, completion_request, LOGPROBS};
+use crate::retry::{send_with_retry, Cooldown, RetryBudget};
+
+pub type CompletionStream = BoxStream<'static, Result<Completion, String>>;
+
+/// Something that turns the document around the cursor into a stream of finished completions.
+/// Rate limits and server errors are retried within `retry_budget`, counted from the first response.
+#[async_trait]
+pub trait CompletionProvider: Send + Sync + fmt::Debug {
+  async fn complete(&self, settings: &CompletionSettings, doc_params: &DocParams, retry_budget: Duration) -> CopilotResult<CompletionStream>;
+}
+
+/// The github copilot proxy, authenticated through the token manager.
+#[derive(Debug)]
+pub struct CopilotProvider {
+  http_client: Arc<Client>,
+  token: Arc<TokenManager>,
+  cooldown: Cooldown,
+}
+
+impl CopilotProvider {
+  pub fn new(http_client: Arc<Client>, token: Arc<TokenManager>) -> Self {
+    Self { http_client, token, cooldown: Cooldown::default() }
+  }
+}
+
+#[async_trait]
+impl CompletionProvider for CopilotProvider {
+  // an expired token gets one refresh and retry before giving up, both share the retry budget
+  async fn complete(&self, settings: &CompletionSettings, doc_params: &DocParams, retry_budget: Duration) -> CopilotResult<CompletionStream> {
+    // signed in but the last token fetch failed, the background refresh may still be waiting
+    if self.token.auth_header().is_none() && self.token.user().is_some() {
+      self.token.refresh().await?;
+    }
+    let body = completion_request(settings, doc_params);
+    let build = || self.token.auth_header().map(|auth_header| build_request(
+      Arc::clone(&self.http_client),
+      auth_header,
+      settings,
+      &body
+    )).ok_or(CopilotError::NotSignedIn);
+    let mut budget = RetryBudget::new(retry_budget);
+    let mut resp = send_with_retry(&self.cooldown, &mut budget, build).await?;
+    if resp.status() == StatusCode::UNAUTHORIZED {
+      self.token.refresh().await?;
+      resp = send_with_retry(&self.cooldown, &mut budget, build).await?;
+    }
+    let resp = resp.error_for_status()?;
+    Ok(choice_stream(resp.bytes_stream(), settings.stop.clone(), settings.n.max(1) as usize).boxed())
+  }
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAiCompletionRequest<'a> {
+  model: &'a str,
+  prompt: &'a str,
+  suffix: &'a str,
+  max_tokens: i32,
+  temperature: f32,
+  top_p: f32,
+  n: i16,
+  #[serde(skip_serializing_if = "<[String]>::is_empty")]
+  stop: &'a [String],
+  stream: bool,
+  logprobs: u8,
+}
+
+/// Any `/v1/completions` server, the suffix is sent alongside the prompt for fill-in-the-middle.
+#[derive(Debug, Default)]
+pub struct OpenAiProvider {
+  http_client: Client,
+  cooldown: Cooldown,
+}
+
+#[async_trait]
+impl CompletionProvider for OpenAiProvider {
+  async fn complete(&self, settings: &CompletionSettings, doc_params: &DocParams, retry_budget: Duration) -> CopilotResult<CompletionStream> {
+    let body = OpenAiCompletionRequest {
+      model: &settings.model,
+      prompt: &doc_params.prefix,
+      suffix: &doc_params.suffix,
+      max_tokens: settings.max_tokens,
+      temperature: settings.temperature,
+      top_p: settings.top_p,
+      n: settings.n,
+      // openai accepts at most four, the rest are still enforced after the response
+      stop: &settings.stop[..settings.stop.len().min(4)],
+      stream: true,
+      logprobs: LOGPROBS,
+    };
+    let api_key = settings.api_key.clone().or_else(|| std::env::var("OPENAI_API_KEY").ok());
+    let build = || {
+      let req = self.http_client.post(settings.completions_url()).json(&body);
+      Ok(match &api_key {
+        Some(api_key) => req.bearer_auth(api_key),
+        None => req,
+      })
+    };
+    let resp = send_with_retry(&self.cooldown, &mut RetryBudget::new(retry_budget), build).await?.error_for_status()?;
+    Ok(choice_stream(resp.bytes_stream(), settings.stop.clone(), settings.n.max(1) as usize).boxed())
+  }
+}
+
+#[derive(Deserialize, Debug)]
+struct GenerateChunk {
+  #[serde(default)]
+  response: String,
+  #[serde(default)]
+  done: bool,
+}
+
+/// A local model behind an Ollama style `/api/generate` endpoint.
+/// These servers produce a single choice per request, so `n` is not honoured.
+#[derive(Debug, Default)]
+pub struct LocalProvider {
+  http_client: Client,
+  cooldown: Cooldown,
+}
+
+#[async_trait]
+impl CompletionProvider for LocalProvider {
+  async fn complete(&self, settings: &CompletionSettings, doc_params: &DocParams, retry_budget: Duration) -> CopilotResult<CompletionStream> {
+    let body = json!({
+      "model": settings.model,
+      "prompt": doc_params.prefix,
+      "suffix": doc_params.suffix,
+      "stream": true,
+      "options": {
+        "num_predict": settings.max_tokens,
+        "temperature": settings.temperature,
+        "top_p": settings.top_p,
+        "stop": settings.stop,
+      }
+    });
+    let build = || Ok(self.http_client.post(settings.completions_url()).json(&body));
+    let resp = send_with_retry(&self.cooldown, &mut RetryBudget::new(retry_budget), build).await?.error_for_status()?;
+    Ok(generate_stream(resp.bytes_stream(), settings.stop.clone()).boxed())
+  }
+}
+
+struct GenerateStreamState<S> {
+  body: S,
+  buffer: Vec<u8>,
+  text: String,
+  stops: Vec<String>,
+  ended: bool,
+  done: bool,
+}
+
+impl<S> GenerateStreamState<S> {
+  // the next complete line, or whatever is left once the body has ended
+  fn next_line(&mut self) -> Option<Vec<u8>> {
+    match self.buffer.iter().position(|&b| b == b'\n') {
+      Some(newline) => Some(self.buffer.drain(..=newline).collect()),
+      None if self.ended && !self.buffer.is_empty() => Some(std::mem::take(&mut self.buffer)),
+      None => None,
+    }
+  }
+}
+
+/// Newline delimited json chunks, concatenated into one completion once `done` arrives
+/// or the text reaches one of `stops`.
+fn generate_stream<S, B, E>(body: S, stops: Vec<String>) -> impl Stream<Item = Result<Completion, String>>
+where
+  S: Stream<Item = Result<B, E>> + Unpin,
+  B: AsRef<[u8]>,
+  E: fmt::Display,
+{
+  let state = GenerateStreamState { body, buffer: vec![], text: String::new(), stops, ended: false, done: false };
+  stream::unfold(state, |mut state| async move {
+    while !state.done {
+      if let Some(line) = state.next_line() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+          continue;
+        }
+        match serde_json::from_slice::<GenerateChunk>(&line) {
+          Ok(chunk) => {
+            let from = state.text.len();
+            state.text.push_str(&chunk.response);
+            let stop = find_stop(&state.text, from, &state.stops);
+            if let Some(stop) = stop {
+              state.text.truncate(stop);
+            }
+            if chunk.done || stop.is_some() {
+              state.done = true;
+              let text = std::mem::take(&mut state.text);
+              return Some((Ok(Completion { index: 0, text, score: None }), state));
+            }
+          },
+          Err(e) => return Some((Err(e.to_string()), state)),
+        }
+        continue;
+      }
+      if state.ended {
+        // the server hung up without finishing, like an unfinished sse choice it is dropped
+        state.done = true;
+        continue;
+      }
+      match state.body.next().await {
+        Some(Ok(bytes)) => state.buffer.extend_from_slice(bytes.as_ref()),
+        Some(Err(e)) => {
+          state.done = true;
+          return Some((Err(e.to_string()), state));
+        },
+        None => state.ended = true,
+      }
+    }
+    None
+  })
+}
+
+/// One instance of every provider, picked per request from the resolved settings.
+#[derive(Debug)]
+pub struct Providers {
+  copilot: CopilotProvider,
+  openai: OpenAiProvider,
+  local: LocalProvider,
+}
+
+impl Providers {
+  pub fn new(copilot_client: Arc<Client>, token: Arc<TokenManager>) -> Self {
+    Self {
+      copilot: CopilotProvider::new(copilot_client, token),
+      openai: OpenAiProvider::default(),
+      local: LocalProvider::default(),
+    }
+  }
+
+  pub fn get(&self, kind: ProviderKind) -> &dyn CompletionProvider {
+    match kind {
+      ProviderKind::Copilot => &self.copilot,
+      ProviderKind::OpenAi => &self.openai,
+      ProviderKind::Local => &self.local,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::auth::CopilotTokenGrant;
+  use crate::copilot::collect_completions;
+  use crate::retry::mock_server;
+
+  fn grant_body() -> String {
+    serde_json::to_string(&CopilotTokenGrant::expiring_in(1500, 1800)).unwrap()
+  }
+
+  fn completion_body(text: &str) -> String {
+    let choice = json!({
+      "id": "cmpl",
+      "model": "copilot-codex",
+      "created": 0,
+      "choices": [{ "text": text, "index": 0, "finish_reason": "stop", "logprobs": null }]
+    });
+    format!("data: {}\n\ndata: [DONE]\n\n", choice)
+  }
+
+  fn copilot(url: &str) -> (CopilotProvider, CompletionSettings) {
+    let token = TokenManager::for_tests(format!("{}/token", url), "octocat");
+    let settings = CompletionSettings { endpoint: Some(url.to_string()), n: 1, ..CompletionSettings::default() };
+    (CopilotProvider::new(Arc::new(Client::new()), Arc::new(token)), settings)
+  }
+
+  #[tokio::test]
+  async fn fetches_a_missing_token_before_completing() {
+    let (url, served) = mock_server::serve(vec![
+      ("200 OK", grant_body()),
+      ("200 OK", completion_body("main()")),
+    ]).await;
+    let (provider, settings) = copilot(&url);
+    assert!(provider.token.auth_header().is_none());
+
+    let completions = provider.complete(&settings, &DocParams::from_text("fn ", ""), Duration::from_secs(5)).await.unwrap();
+    assert_eq!(collect_completions(completions).await.0[0].text, "main()");
+    assert!(provider.token.auth_header().is_some());
+    assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 2);
+  }
+
+  #[tokio::test]
+  async fn refreshes_and_retries_once_on_401() {
+    let (url, served) = mock_server::serve(vec![
+      ("200 OK", grant_body()),
+      ("401 Unauthorized", String::new()),
+      ("200 OK", grant_body()),
+      ("200 OK", completion_body("main()")),
+    ]).await;
+    let (provider, settings) = copilot(&url);
+    provider.token.refresh().await.unwrap();
+
+    let completions = provider.complete(&settings, &DocParams::from_text("fn ", ""), Duration::from_secs(5)).await.unwrap();
+    let (completions, _) = collect_completions(completions).await;
+    assert_eq!(completions[0].text, "main()");
+    assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 4);
+  }
+
+  #[tokio::test]
+  async fn joins_generate_chunks_into_one_completion() {
+    let chunks = vec![
+      "{\"response\":\"fn \",\"done\":false}\n{\"resp",
+      "onse\":\"main()\",\"done\":false}\n",
+      "{\"response\":\"\",\"done\":true}",
+    ];
+    let body = stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>));
+    let (completions, reason) = collect_completions(generate_stream(body, vec![])).await;
+    assert_eq!(completions, vec![Completion { index: 0, text: "fn main()".to_string(), score: None }]);
+    assert_eq!(reason, None);
+
+    let chunks = vec!["{\"response\":\"fn main()\\n\",\"done\":false}\n"];
+    let body = stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>)).chain(stream::pending());
+    let (completions, _) = collect_completions(generate_stream(body, vec!["\n".to_string()])).await;
+    assert_eq!(completions[0].text, "fn main()");
+  }
+}
@@ -2,7 +2,13 @@ use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::Utc;
 use std::sync::Arc;
-use reqwest::{RequestBuilder, Client};
+use reqwest::{RequestBuilder, Client, header::HeaderValue};
+use crate::config::CompletionSettings;
+use crate::copilot::DocParams;
+use crate::tokenizer::count_tokens;
+
+// top alternatives requested per token, the sampled token's own logprob ranks the choices
+pub const LOGPROBS: u8 = 2;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CopilotCompletionRequest {
@@ -12,9 +18,12 @@ pub struct CopilotCompletionRequest {
   pub temperature: f32,
   pub top_p: f32,
   pub n: i16,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
   pub stop: Vec<String>,
-  pub nwo: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub nwo: Option<String>,
   pub stream: bool,
+  pub logprobs: u8,
   pub extra: CopilotCompletionParams
 }
 
@@ -27,34 +36,38 @@ pub struct CopilotCompletionParams {
   pub suffix_tokens: i32
 }
 
-pub fn build_request(
-  http_client: Arc<Client>,
-  language: String,
-  prompt: String,
-  suffix: String
-) -> RequestBuilder {
-  let extra = CopilotCompletionParams { language: language.to_string(),
-    next_indent: 0,
-    trim_by_indentation: true,
-    prompt_tokens: prompt.len() as i32,
-    suffix_tokens: suffix.len() as i32
+/// The request body, built once per completion so the tokens are not counted again on every retry.
+pub fn completion_request(settings: &CompletionSettings, doc_params: &DocParams) -> CopilotCompletionRequest {
+  let extra = CopilotCompletionParams { language: doc_params.language.to_string(),
+    next_indent: doc_params.next_indent.min(i8::MAX as usize) as i8,
+    trim_by_indentation: settings.trim_by_indentation,
+    prompt_tokens: count_tokens(&doc_params.prefix) as i32,
+    suffix_tokens: count_tokens(&doc_params.suffix) as i32
   };
-  let body = Some(CopilotCompletionRequest {
-    prompt,
-    suffix,
-    max_tokens: 500,
-    temperature: 1.0,
-    top_p: 1.0,
-    n: 3,
-    stop: ["unset".to_string()].to_vec(),
-    nwo: "my_org/my_repo".to_string(),
+  CopilotCompletionRequest {
+    prompt: doc_params.prefix.to_string(),
+    suffix: doc_params.suffix.to_string(),
+    max_tokens: settings.max_tokens,
+    temperature: settings.temperature,
+    top_p: settings.top_p,
+    n: settings.n,
+    stop: settings.stop.clone(),
+    nwo: doc_params.nwo.clone(),
     stream: true,
+    logprobs: LOGPROBS,
     extra
-  });
-  let body = serde_json::to_string(&body).unwrap();
-  let completions_url = "https://copilot-proxy.githubusercontent.com/v1/engines/copilot-codex/completions";
-  http_client.post(completions_url)
+  }
+}
+
+pub fn build_request(
+  http_client: Arc<Client>,
+  auth_header: HeaderValue,
+  settings: &CompletionSettings,
+  body: &CopilotCompletionRequest
+) -> RequestBuilder {
+  http_client.post(settings.completions_url())
+    .header("Authorization", auth_header)
     .header("X-Request-Id", Uuid::new_v4().to_string())
     .header("VScode-SessionId", Uuid::new_v4().to_string() + &Utc::now().timestamp().to_string())
-    .body(body)
+    .json(body)
 }
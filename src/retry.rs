@@ -0,0 +1,255 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use reqwest::{header::{HeaderMap, RETRY_AFTER}, RequestBuilder, Response, StatusCode};
+use uuid::Uuid;
+use crate::error::{CopilotError, CopilotResult};
+
+// well under the debounce delay retries have to fit in
+const BASE_DELAY: Duration = Duration::from_millis(25);
+const MAX_DELAY: Duration = Duration::from_secs(2);
+const MAX_ATTEMPTS: u32 = 4;
+
+/// How long a 429 asks to wait, given either in seconds or as an http date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+  let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+  if let Ok(secs) = value.parse::<u64>() {
+    return Some(Duration::from_secs(secs));
+  }
+  let date = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+  Some((date - Utc::now()).to_std().unwrap_or_default())
+}
+
+/// Exponential delay before the given retry, with up to half of it taken off at random.
+pub fn backoff(retry: u32) -> Duration {
+  let delay = BASE_DELAY.saturating_mul(2u32.saturating_pow(retry)).min(MAX_DELAY);
+  let jitter = (Uuid::new_v4().as_u128() % 1000) as u32;
+  delay - delay / 2 * jitter / 1000
+}
+
+fn is_transient(status: StatusCode) -> bool {
+  matches!(
+    status,
+    StatusCode::INTERNAL_SERVER_ERROR | StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+  )
+}
+
+/// The time a request may spend waiting between retries. The clock starts with the first response,
+/// so the round trip itself does not use it up, and keeps running across every send sharing it.
+#[derive(Debug)]
+pub struct RetryBudget {
+  budget: Duration,
+  deadline: Option<Instant>,
+}
+
+impl RetryBudget {
+  pub fn new(budget: Duration) -> Self {
+    Self { budget, deadline: None }
+  }
+
+  fn fits(&mut self, delay: Duration) -> bool {
+    let deadline = *self.deadline.get_or_insert_with(|| Instant::now() + self.budget);
+    Instant::now() + delay <= deadline
+  }
+}
+
+/// Remembers how long a server asked to be left alone after a rate limit we did not wait out.
+#[derive(Debug, Default)]
+pub struct Cooldown {
+  until: Mutex<Option<Instant>>,
+}
+
+impl Cooldown {
+  pub fn remaining(&self) -> Option<Duration> {
+    let until = (*self.until.lock().unwrap())?;
+    until.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())
+  }
+
+  fn start(&self, wait: Duration) {
+    *self.until.lock().unwrap() = Some(Instant::now() + wait);
+  }
+}
+
+/// Sends the request built by `build`, building it again for every retry.
+/// Rate limits are waited out when `Retry-After` fits in the budget, rate limits without it and
+/// transient server errors are retried with backoff, anything else is returned to the caller as is.
+/// A longer `Retry-After` is answered locally with `RateLimited` until it has passed.
+pub async fn send_with_retry<F>(cooldown: &Cooldown, budget: &mut RetryBudget, build: F) -> CopilotResult<Response>
+where
+  F: Fn() -> CopilotResult<RequestBuilder>,
+{
+  if let Some(wait) = cooldown.remaining() {
+    return Err(CopilotError::RateLimited(Some(wait)));
+  }
+  let mut retry = 0;
+  loop {
+    let resp = build()?.send().await?;
+    let status = resp.status();
+    let delay = match status {
+      StatusCode::TOO_MANY_REQUESTS => {
+        let wait = retry_after(resp.headers());
+        let delay = wait.unwrap_or_else(|| backoff(retry));
+        if (wait.is_none() && retry + 1 >= MAX_ATTEMPTS) || !budget.fits(delay) {
+          if let Some(wait) = wait {
+            cooldown.start(wait);
+          }
+          return Err(CopilotError::RateLimited(wait));
+        }
+        delay
+      },
+      status if is_transient(status) => {
+        let delay = backoff(retry);
+        if retry + 1 >= MAX_ATTEMPTS || !budget.fits(delay) {
+          return Err(CopilotError::Upstream(status));
+        }
+        delay
+      },
+      _ => return Ok(resp),
+    };
+    retry += 1;
+    tokio::time::sleep(delay).await;
+  }
+}
+
+/// A local http server for tests.
+#[cfg(test)]
+pub(crate) mod mock_server {
+  use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+  use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+  // the whole request has to be read, closing with unread data resets the connection
+  pub async fn read_request(socket: &mut TcpStream) {
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+    loop {
+      let Ok(read @ 1..) = socket.read(&mut buf).await else { return };
+      request.extend_from_slice(&buf[..read]);
+      let text = String::from_utf8_lossy(&request);
+      let Some(head_end) = text.find("\r\n\r\n") else { continue };
+      let content_length = text[..head_end].lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+      if request.len() >= head_end + 4 + content_length {
+        return;
+      }
+    }
+  }
+
+  /// Answers each connection with the next status line, extra headers included, and body.
+  /// Returns the server's url and how many requests were answered so far.
+  pub async fn serve(responses: Vec<(&'static str, String)>) -> (String, Arc<AtomicUsize>) {
+    serve_after(Duration::ZERO, responses).await
+  }
+
+  /// Like `serve`, taking `latency` to answer each request.
+  pub async fn serve_after(latency: Duration, responses: Vec<(&'static str, String)>) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let served = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&served);
+    tokio::spawn(async move {
+      for (status, body) in responses {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        read_request(&mut socket).await;
+        tokio::time::sleep(latency).await;
+        let response = format!("HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", status, body.len(), body);
+        socket.write_all(response.as_bytes()).await.unwrap();
+        counter.fetch_add(1, Ordering::SeqCst);
+      }
+    });
+    (url, served)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+  use reqwest::header::HeaderValue;
+
+  #[test]
+  fn parses_retry_after() {
+    let mut headers = HeaderMap::new();
+    assert_eq!(retry_after(&headers), None);
+    headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+    assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+    headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+    assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+  }
+
+  #[test]
+  fn backs_off_exponentially_with_jitter() {
+    for retry in 0..8 {
+      let full = BASE_DELAY.saturating_mul(2u32.pow(retry)).min(MAX_DELAY);
+      let delay = backoff(retry);
+      assert!(delay <= full && delay >= full / 2, "{:?} for retry {}", delay, retry);
+    }
+  }
+
+  async fn serve(statuses: Vec<&'static str>) -> (String, Arc<AtomicUsize>) {
+    mock_server::serve(statuses.into_iter().map(|status| (status, String::new())).collect()).await
+  }
+
+  #[tokio::test]
+  async fn retries_transient_errors() {
+    let (url, served) = serve(vec!["502 Bad Gateway", "503 Service Unavailable", "200 OK"]).await;
+    let client = reqwest::Client::new();
+    let resp = send_with_retry(&Cooldown::default(), &mut RetryBudget::new(Duration::from_secs(5)), || Ok(client.get(&url))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(served.load(Ordering::SeqCst), 3);
+  }
+
+  #[tokio::test]
+  async fn backs_off_on_rate_limits_without_retry_after() {
+    let (url, served) = serve(vec!["429 Too Many Requests", "200 OK"]).await;
+    let client = reqwest::Client::new();
+    let resp = send_with_retry(&Cooldown::default(), &mut RetryBudget::new(Duration::from_secs(5)), || Ok(client.get(&url))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(served.load(Ordering::SeqCst), 2);
+
+    // the first backoff already takes longer than what is left
+    let (url, served) = serve(vec!["429 Too Many Requests"]).await;
+    let err = send_with_retry(&Cooldown::default(), &mut RetryBudget::new(Duration::from_millis(10)), || Ok(client.get(&url))).await.unwrap_err();
+    assert!(matches!(err, CopilotError::RateLimited(None)));
+    assert_eq!(served.load(Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn gives_up_on_long_rate_limits() {
+    let (url, served) = serve(vec!["429 Too Many Requests\r\nretry-after: 120"]).await;
+    let client = reqwest::Client::new();
+    let cooldown = Cooldown::default();
+    let err = send_with_retry(&cooldown, &mut RetryBudget::new(Duration::from_secs(5)), || Ok(client.get(&url))).await.unwrap_err();
+    assert!(matches!(err, CopilotError::RateLimited(Some(wait)) if wait == Duration::from_secs(120)));
+    assert_eq!(served.load(Ordering::SeqCst), 1);
+
+    // the server is left alone until the wait has passed
+    let err = send_with_retry(&cooldown, &mut RetryBudget::new(Duration::from_secs(5)), || Ok(client.get(&url))).await.unwrap_err();
+    assert!(matches!(err, CopilotError::RateLimited(Some(wait)) if wait > Duration::from_secs(100)));
+    assert_eq!(served.load(Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn starts_the_budget_at_the_first_response() {
+    let (url, served) = mock_server::serve_after(
+      Duration::from_millis(150),
+      vec![("502 Bad Gateway", String::new()), ("200 OK", String::new())],
+    ).await;
+    let client = reqwest::Client::new();
+    let mut budget = RetryBudget::new(Duration::from_millis(100));
+    let resp = send_with_retry(&Cooldown::default(), &mut budget, || Ok(client.get(&url))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(served.load(Ordering::SeqCst), 2);
+  }
+
+  #[tokio::test]
+  async fn reports_rejected_credentials_as_auth_errors() {
+    let (url, served) = serve(vec!["401 Unauthorized"]).await;
+    let client = reqwest::Client::new();
+    let resp = send_with_retry(&Cooldown::default(), &mut RetryBudget::new(Duration::from_secs(5)), || Ok(client.get(&url))).await.unwrap();
+    let err = CopilotError::from(resp.error_for_status().unwrap_err());
+    assert!(matches!(err, CopilotError::Auth(_)), "{:?}", err);
+    assert_eq!(served.load(Ordering::SeqCst), 1);
+  }
+}
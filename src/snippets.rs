@@ -0,0 +1,160 @@
+use std::{borrow::Cow, collections::{HashMap, HashSet}};
+use ropey::Rope;
+use crate::language::Language;
+use crate::tokenizer::count_tokens;
+
+// lines per window, both around the cursor and in the other documents
+const WINDOW_LINES: usize = 20;
+const MAX_SNIPPETS: usize = 4;
+
+// keywords common enough to make unrelated code look similar
+const STOP_WORDS: &[&str] = &[
+  "if", "else", "for", "while", "return", "let", "const", "var", "fn", "function", "def", "class",
+  "import", "from", "use", "pub", "self", "this", "true", "false", "null", "None", "new", "in", "of",
+  "and", "or", "not", "the", "a", "to", "is",
+];
+
+fn tokens(line: &str) -> impl Iterator<Item = &str> {
+  line.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+    .filter(|token| !token.is_empty() && !STOP_WORDS.contains(token))
+}
+
+/// Distinct tokens of a sliding window, kept alongside how many of them the reference shares.
+struct WindowTokens<'a, 'r> {
+  reference: &'r HashSet<&'r str>,
+  counts: HashMap<&'a str, usize>,
+  shared: usize,
+}
+
+impl<'a, 'r> WindowTokens<'a, 'r> {
+  fn add(&mut self, line: &'a str) {
+    for token in tokens(line) {
+      let count = self.counts.entry(token).or_default();
+      if *count == 0 && self.reference.contains(token) {
+        self.shared += 1;
+      }
+      *count += 1;
+    }
+  }
+
+  fn remove(&mut self, line: &'a str) {
+    for token in tokens(line) {
+      let Some(count) = self.counts.get_mut(token) else { continue };
+      *count -= 1;
+      if *count == 0 {
+        self.counts.remove(token);
+        if self.reference.contains(token) {
+          self.shared -= 1;
+        }
+      }
+    }
+  }
+
+  fn jaccard(&self) -> f32 {
+    let union = self.reference.len() + self.counts.len() - self.shared;
+    if union == 0 { 0.0 } else { self.shared as f32 / union as f32 }
+  }
+}
+
+/// Similarity of every window of `lines` to the reference, by starting line.
+fn score_windows(reference: &HashSet<&str>, lines: &[&str]) -> Vec<f32> {
+  let size = WINDOW_LINES.min(lines.len());
+  let mut window = WindowTokens { reference, counts: HashMap::new(), shared: 0 };
+  lines[..size].iter().for_each(|line| window.add(line));
+  let mut scores = vec![window.jaccard()];
+  for start in 1..=lines.len() - size {
+    window.remove(lines[start - 1]);
+    window.add(lines[start + size - 1]);
+    scores.push(window.jaccard());
+  }
+  scores
+}
+
+fn format_snippet(language: &Language, path: &str, lines: &[&str]) -> String {
+  let mut snippet = format!("{}\n", language.comment(&format!("Compare this snippet from {}:", path)));
+  for line in lines {
+    snippet.push_str(language.comment(line).trim_end());
+    snippet.push('\n');
+  }
+  snippet
+}
+
+// lines of a rope, borrowed wherever they don't straddle a chunk boundary
+fn rope_lines(rope: &Rope) -> Vec<Cow<str>> {
+  let mut lines: Vec<Cow<str>> = rope.lines().map(Cow::from).collect();
+  // like str::lines, a trailing line break doesn't start another line
+  if lines.last().is_some_and(|line| line.is_empty()) {
+    lines.pop();
+  }
+  lines
+}
+
+/// Commented windows of `documents`, given as path and text, that are most similar to the end of `prefix`.
+/// Up to four windows that fit in `budget` tokens are returned, best match first.
+pub fn neighbor_snippets(language: &Language, prefix: &str, documents: &[(String, Rope)], budget: usize) -> String {
+  let prefix_lines: Vec<&str> = prefix.lines().collect();
+  let reference: HashSet<&str> = prefix_lines[prefix_lines.len().saturating_sub(WINDOW_LINES)..].iter()
+    .flat_map(|line| tokens(line))
+    .collect();
+  if reference.is_empty() || budget == 0 {
+    return String::new();
+  }
+
+  let owned_lines: Vec<Vec<Cow<str>>> = documents.iter().map(|(_, rope)| rope_lines(rope)).collect();
+  let documents: Vec<(&str, Vec<&str>)> = documents.iter().zip(&owned_lines)
+    .map(|((path, _), lines)| (path.as_str(), lines.iter().map(|line| line.as_ref()).collect::<Vec<_>>()))
+    .filter(|(_, lines)| !lines.is_empty())
+    .collect();
+  let mut windows: Vec<(usize, usize, f32)> = documents.iter().enumerate()
+    .flat_map(|(doc, (_, lines))| {
+      score_windows(&reference, lines).into_iter().enumerate()
+        .map(move |(start, score)| (doc, start, score))
+    })
+    .filter(|&(_, _, score)| score > 0.0)
+    .collect();
+  windows.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+  let mut picked: Vec<(usize, usize)> = vec![];
+  let mut snippets = String::new();
+  let mut used = 0;
+  for (doc, start, _) in windows {
+    if picked.len() == MAX_SNIPPETS {
+      break;
+    }
+    // windows overlapping an already chosen one would repeat the same code
+    if picked.iter().any(|&(picked_doc, picked_start)| picked_doc == doc && picked_start.abs_diff(start) < WINDOW_LINES) {
+      continue;
+    }
+    let (path, lines) = &documents[doc];
+    let snippet = format_snippet(language, path, &lines[start..(start + WINDOW_LINES).min(lines.len())]);
+    let tokens = count_tokens(&snippet);
+    if used + tokens > budget {
+      continue;
+    }
+    used += tokens;
+    picked.push((doc, start));
+    snippets.push_str(&snippet);
+  }
+  snippets
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn picks_the_most_similar_window() {
+    let rust = Language::find("rust").unwrap();
+    let prefix = "fn parse_header(input: &str) -> Header {\n  let (name, value) = input.split_once(':').unwrap();\n";
+    let unrelated = (0..60).map(|i| format!("const COLOR_{}: u32 = {};", i, i)).collect::<Vec<_>>().join("\n");
+    let documents = vec![
+      ("src/colors.rs".to_string(), Rope::from_str(&unrelated)),
+      ("src/headers.rs".to_string(), Rope::from_str("struct Header { name: String, value: String }\n\nfn split_header(input: &str) -> (String, String) {\n  let (name, value) = input.split_once(':').unwrap();\n  (name.into(), value.into())\n}\n")),
+    ];
+
+    let snippets = neighbor_snippets(rust, prefix, &documents, 1000);
+    assert!(snippets.starts_with("// Compare this snippet from src/headers.rs:\n// struct Header { name: String, value: String }\n//\n"));
+    assert!(!snippets.contains("colors"));
+    assert_eq!(neighbor_snippets(rust, prefix, &documents, 10), "");
+  }
+}
@@ -0,0 +1,189 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}, sync::OnceLock};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use fancy_regex::Regex;
+
+// openai's cl100k_base ranks, one `<base64 token> <rank>` pair per line
+const CL100K_BASE: &str = include_str!("../vendor/cl100k_base.tiktoken");
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Byte pair encoder that only counts tokens, the ids themselves are never needed.
+struct Tokenizer {
+  ranks: HashMap<Vec<u8>, u32>,
+  pattern: Regex,
+}
+
+fn tokenizer() -> &'static Tokenizer {
+  static TOKENIZER: OnceLock<Tokenizer> = OnceLock::new();
+  TOKENIZER.get_or_init(|| {
+    let ranks = CL100K_BASE.lines()
+      .filter_map(|line| {
+        let (token, rank) = line.split_once(' ')?;
+        Some((STANDARD.decode(token).ok()?, rank.parse().ok()?))
+      })
+      .collect();
+    Tokenizer { ranks, pattern: Regex::new(CL100K_PATTERN).unwrap() }
+  })
+}
+
+impl Tokenizer {
+  // merges the lowest ranked adjacent pair, leftmost first, until nothing merges.
+  // every single byte has a rank. Parts are a linked list over their start offsets and candidate
+  // merges sit in a heap, entries that went stale when a neighbour merged are skipped
+  fn piece_tokens(&self, piece: &[u8]) -> usize {
+    if self.ranks.contains_key(piece) {
+      return 1;
+    }
+    let len = piece.len();
+    let mut next: Vec<usize> = (1..=len).collect();
+    let mut prev: Vec<usize> = (0..len).map(|i| i.saturating_sub(1)).collect();
+    let mut alive = vec![true; len];
+    let mut parts = len;
+    let mut merges = BinaryHeap::new();
+    let push = |merges: &mut BinaryHeap<Reverse<(u32, usize, usize)>>, next: &[usize], start: usize| {
+      let mid = next[start];
+      if mid < len {
+        if let Some(&rank) = self.ranks.get(&piece[start..next[mid]]) {
+          merges.push(Reverse((rank, start, next[mid])));
+        }
+      }
+    };
+    for start in 0..len {
+      push(&mut merges, &next, start);
+    }
+    while let Some(Reverse((_, start, end))) = merges.pop() {
+      let mid = next[start];
+      if !alive[start] || mid >= len || next[mid] != end {
+        continue;
+      }
+      alive[mid] = false;
+      next[start] = end;
+      if end < len {
+        prev[end] = start;
+      }
+      parts -= 1;
+      push(&mut merges, &next, start);
+      if start > 0 {
+        push(&mut merges, &next, prev[start]);
+      }
+    }
+    parts
+  }
+
+  fn pieces<'a>(&'a self, text: &'a str) -> impl Iterator<Item = (usize, usize)> + 'a {
+    self.pattern.find_iter(text)
+      .filter_map(Result::ok)
+      .map(|piece| (piece.start(), self.piece_tokens(piece.as_str().as_bytes())))
+  }
+}
+
+pub fn count_tokens(text: &str) -> usize {
+  tokenizer().pieces(text).map(|(_, tokens)| tokens).sum()
+}
+
+// first index whose lines, taken in the given order, no longer fit the budget
+fn lines_within<'a>(lines: impl Iterator<Item = &'a str>, mut budget: usize) -> usize {
+  let mut kept = 0;
+  for line in lines {
+    let tokens = count_tokens(line);
+    if tokens > budget {
+      break;
+    }
+    budget -= tokens;
+    kept += 1;
+  }
+  kept
+}
+
+/// Drops whole lines from the top of `prefix` until it fits in `budget` tokens.
+/// A cursor line over the budget on its own, e.g. in minified code, keeps only its end.
+pub fn trim_prefix(prefix: &str, budget: usize) -> String {
+  let lines: Vec<&str> = prefix.split_inclusive('\n').collect();
+  let Some((cursor_line, above)) = lines.split_last() else {
+    return String::new();
+  };
+  let tokens = count_tokens(cursor_line);
+  if tokens > budget {
+    // the pieces of the line furthest from the cursor go first
+    let pieces: Vec<(usize, usize)> = tokenizer().pieces(cursor_line).collect();
+    let mut left = tokens;
+    let start = pieces.iter()
+      .find(|(_, piece_tokens)| {
+        let fits = left <= budget;
+        left -= piece_tokens;
+        fits
+      })
+      .map_or(cursor_line.len(), |(start, _)| *start);
+    return cursor_line[start..].to_string();
+  }
+  let kept = lines_within(above.iter().rev().copied(), budget - tokens);
+  format!("{}{}", above[above.len() - kept..].concat(), cursor_line)
+}
+
+/// Drops whole lines from the bottom of `suffix` until it fits in `budget` tokens.
+/// A first line over the budget on its own keeps only its start.
+pub fn trim_suffix(suffix: &str, budget: usize) -> String {
+  let lines: Vec<&str> = suffix.split_inclusive('\n').collect();
+  let Some((cursor_line, below)) = lines.split_first() else {
+    return String::new();
+  };
+  let tokens = count_tokens(cursor_line);
+  if tokens > budget {
+    let mut used = 0;
+    let end = tokenizer().pieces(cursor_line)
+      .find(|(_, piece_tokens)| {
+        used += piece_tokens;
+        used > budget
+      })
+      .map_or(cursor_line.len(), |(start, _)| start);
+    return cursor_line[..end].to_string();
+  }
+  let kept = lines_within(below.iter().copied(), budget - tokens);
+  format!("{}{}", cursor_line, below[..kept].concat())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_cl100k_tokens() {
+    assert_eq!(count_tokens(""), 0);
+    assert_eq!(count_tokens("hello world"), 2);
+    assert_eq!(count_tokens("fn main() {\n    println!(\"hi\");\n}\n"), 10);
+    assert_eq!(count_tokens("tiktoken is great!"), 6);
+  }
+
+  #[test]
+  fn trims_at_line_boundaries() {
+    let prefix = "first line\nsecond line\nthird line\nlet x = ";
+    assert_eq!(trim_prefix(prefix, 7), "third line\nlet x = ");
+    assert_eq!(trim_prefix(prefix, 4), "let x = ");
+    assert_eq!(trim_prefix(prefix, 0), "");
+    assert_eq!(trim_prefix(prefix, 1000), prefix);
+
+    let suffix = ";\nfirst line\nsecond line\n";
+    assert_eq!(trim_suffix(suffix, 5), ";\nfirst line\n");
+    assert_eq!(trim_suffix(suffix, 1), ";\n");
+    assert_eq!(trim_suffix(suffix, 0), "");
+  }
+
+  #[test]
+  fn trims_single_lines_over_the_budget() {
+    // minified code has everything on the cursor line
+    let prefix = format!("{}let x = ", "a=1;".repeat(1000));
+    let trimmed = trim_prefix(&prefix, 16);
+    assert!(trimmed.ends_with("let x = ") && trimmed.len() < 100, "{:?}", trimmed);
+    assert!((12..=16).contains(&count_tokens(&trimmed)));
+
+    let suffix = format!(";{}\nlast line", "foo();".repeat(1000));
+    let trimmed = trim_suffix(&suffix, 10);
+    assert!(trimmed.starts_with(";foo();") && !trimmed.contains('\n'), "{:?}", trimmed);
+    assert!((7..=10).contains(&count_tokens(&trimmed)));
+  }
+
+  #[test]
+  fn counts_long_pieces_quickly() {
+    // one pretokenizer piece, merging it pair by pair would take quadratic time
+    assert!(count_tokens(&"=".repeat(200_000)) < 200_000);
+  }
+}